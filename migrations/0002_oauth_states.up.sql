@@ -0,0 +1,7 @@
+-- Pending OAuth authorization requests, keyed by the CSRF state sent to the provider.
+-- Rows are consumed by the callback and are only valid until expires_at.
+CREATE TABLE oauth_states (
+    state VARCHAR(255) PRIMARY KEY,
+    created_at TIMESTAMPTZ DEFAULT (NOW() AT TIME ZONE 'utc'),
+    expires_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,5 @@
+-- Store the PKCE code verifier next to the CSRF state so the callback can redeem the code.
+-- Pending states are short lived, so dropping them only forces in-flight logins to restart.
+DELETE FROM oauth_states;
+
+ALTER TABLE oauth_states ADD COLUMN pkce_verifier TEXT NOT NULL;
@@ -0,0 +1,4 @@
+-- Remember which provider a state was issued for so it cannot be redeemed at another callback.
+DELETE FROM oauth_states;
+
+ALTER TABLE oauth_states ADD COLUMN provider VARCHAR(255) NOT NULL;
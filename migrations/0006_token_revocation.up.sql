@@ -0,0 +1,16 @@
+-- Access tokens revoked before their expiry, checked by jti on every request.
+-- Rows can be dropped once expires_at has passed since the token is dead anyway.
+CREATE TABLE revoked_tokens (
+    jti VARCHAR(255) PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ DEFAULT (NOW() AT TIME ZONE 'utc'),
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+-- Every access token of the user issued at or before revoked_before is rejected.
+CREATE TABLE user_token_revocations (
+    user_id INTEGER PRIMARY KEY,
+    revoked_before TIMESTAMPTZ NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
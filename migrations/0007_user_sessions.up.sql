@@ -0,0 +1,21 @@
+-- One row per login, its id is the `sid` claim of the access tokens and the
+-- family id of the refresh tokens issued for that login.
+CREATE TABLE user_sessions (
+    id UUID PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    user_agent TEXT,
+    ip_address VARCHAR(45),
+    created_at TIMESTAMPTZ DEFAULT (NOW() AT TIME ZONE 'utc'),
+    last_seen_at TIMESTAMPTZ DEFAULT (NOW() AT TIME ZONE 'utc'),
+    revoked_at TIMESTAMPTZ,
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+CREATE INDEX user_sessions_user_id_idx ON user_sessions (user_id);
+
+-- Logins from before sessions existed keep working through their refresh token family
+INSERT INTO user_sessions (id, user_id, created_at, last_seen_at, revoked_at)
+SELECT family_id, user_id, MIN(created_at), MAX(created_at),
+       CASE WHEN BOOL_AND(revoked_at IS NOT NULL) THEN MAX(revoked_at) END
+FROM refresh_tokens
+GROUP BY family_id, user_id;
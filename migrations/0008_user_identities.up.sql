@@ -0,0 +1,34 @@
+-- A user can log in with several provider identities, each one is unique per provider
+CREATE TABLE user_identities (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    provider VARCHAR(255) NOT NULL,
+    provider_user_id VARCHAR(255) NOT NULL,
+    email VARCHAR(255) NOT NULL,
+    email_verified BOOLEAN NOT NULL DEFAULT FALSE,
+    refresh_token TEXT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT (NOW() AT TIME ZONE 'utc'),
+    UNIQUE (provider, provider_user_id),
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+CREATE INDEX user_identities_user_id_idx ON user_identities (user_id);
+
+-- Only accounts whose email was verified are linked automatically
+ALTER TABLE users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Google and GitHub only ever gave us verified addresses
+UPDATE users SET email_verified = oauth_provider IN ('google', 'github');
+
+-- Every existing user becomes their own single identity, the refresh token keeps its encryption
+INSERT INTO user_identities (user_id, provider, provider_user_id, email, email_verified, refresh_token, created_at)
+SELECT id, oauth_provider, oauth_id, email, email_verified, oauth_refresh_token, created_at
+FROM users;
+
+ALTER TABLE users
+    DROP COLUMN oauth_provider,
+    DROP COLUMN oauth_id,
+    DROP COLUMN oauth_refresh_token;
+
+-- Set when the login flow was started to link a provider to an existing user
+ALTER TABLE oauth_states ADD COLUMN link_user_id INTEGER REFERENCES users(id) ON DELETE CASCADE;
@@ -0,0 +1,16 @@
+-- Argon2id PHC hashes for users that log in with email and password
+CREATE TABLE user_passwords (
+    user_id INTEGER PRIMARY KEY,
+    password_hash TEXT NOT NULL,
+    updated_at TIMESTAMPTZ DEFAULT (NOW() AT TIME ZONE 'utc'),
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+-- One-time password reset tokens, stored as SHA-256 hashes
+CREATE TABLE password_reset_tokens (
+    token_hash VARCHAR(64) PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT (NOW() AT TIME ZONE 'utc'),
+    expires_at TIMESTAMPTZ NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
@@ -0,0 +1,6 @@
+-- Magic links are signed tokens, only their use is recorded to make them single use.
+-- Rows can be deleted once the link expired.
+CREATE TABLE used_magic_links (
+    jti VARCHAR(64) PRIMARY KEY,
+    expires_at TIMESTAMPTZ NOT NULL
+);
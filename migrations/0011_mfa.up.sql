@@ -0,0 +1,20 @@
+-- TOTP second factor, the secret is encrypted like the OAuth refresh tokens
+CREATE TABLE user_mfa (
+    user_id INTEGER PRIMARY KEY,
+    totp_secret TEXT NOT NULL,
+    enabled_at TIMESTAMPTZ,
+    last_used_step BIGINT,
+    created_at TIMESTAMPTZ DEFAULT (NOW() AT TIME ZONE 'utc'),
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+-- Single use codes to log in without the authenticator, stored as SHA-256 hashes
+CREATE TABLE mfa_recovery_codes (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    code_hash VARCHAR(64) NOT NULL,
+    used_at TIMESTAMPTZ,
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+CREATE INDEX mfa_recovery_codes_user_id_idx ON mfa_recovery_codes (user_id);
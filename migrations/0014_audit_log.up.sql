@@ -0,0 +1,14 @@
+-- Append-only record of privileged actions, like admins impersonating users
+CREATE TABLE audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    actor_user_id INTEGER NOT NULL,
+    action VARCHAR(100) NOT NULL,
+    target_user_id INTEGER,
+    reason TEXT,
+    ip_address VARCHAR(45),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    FOREIGN KEY (actor_user_id) REFERENCES users(id),
+    FOREIGN KEY (target_user_id) REFERENCES users(id)
+);
+
+CREATE INDEX audit_log_target_user_id_idx ON audit_log (target_user_id, created_at);
@@ -0,0 +1,14 @@
+-- Stripe subscription states, one-time purchases stay 'active'
+CREATE TYPE subscription_status AS ENUM (
+    'incomplete', 'incomplete_expired', 'trialing', 'active', 'past_due', 'canceled', 'unpaid', 'paused'
+);
+
+-- Recurring subscriptions are paid by invoices, they have no single payment
+ALTER TABLE user_subscription
+    ALTER COLUMN stripe_payment_id DROP NOT NULL,
+    ADD COLUMN stripe_subscription_id VARCHAR(255) UNIQUE,
+    ADD COLUMN status subscription_status NOT NULL DEFAULT 'active',
+    ADD COLUMN current_period_start TIMESTAMPTZ,
+    ADD COLUMN current_period_end TIMESTAMPTZ;
+
+UPDATE user_subscription SET status = 'canceled' WHERE is_active = false;
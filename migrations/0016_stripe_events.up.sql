@@ -0,0 +1,6 @@
+-- Webhook events that were processed, Stripe redelivers events until they are acknowledged
+CREATE TABLE stripe_events (
+    id VARCHAR(255) PRIMARY KEY,
+    event_type VARCHAR(255) NOT NULL,
+    processed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
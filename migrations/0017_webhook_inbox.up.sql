@@ -0,0 +1,16 @@
+CREATE TYPE inbox_event_status AS ENUM ('pending', 'processed', 'dead');
+
+-- Verified Stripe events, stored before they are acknowledged and processed by the worker
+CREATE TABLE webhook_inbox (
+    id VARCHAR(255) PRIMARY KEY,
+    event_type VARCHAR(255) NOT NULL,
+    payload TEXT NOT NULL,
+    status inbox_event_status NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_error TEXT,
+    received_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    processed_at TIMESTAMPTZ
+);
+
+CREATE INDEX webhook_inbox_due_idx ON webhook_inbox (next_attempt_at) WHERE status = 'pending';
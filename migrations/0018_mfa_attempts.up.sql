@@ -0,0 +1,12 @@
+-- Wrong second factor codes since failed_attempts_since, the factor locks once too
+-- many of them fall within the window.
+ALTER TABLE user_mfa
+    ADD COLUMN failed_attempts INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN failed_attempts_since TIMESTAMPTZ;
+
+-- MFA pending tokens are signed like magic links, only their use is recorded to
+-- make them single use. Rows can be deleted once the token expired.
+CREATE TABLE used_mfa_pending_tokens (
+    jti VARCHAR(64) PRIMARY KEY,
+    expires_at TIMESTAMPTZ NOT NULL
+);
@@ -71,9 +71,16 @@ impl ResponseError for ApiError {
             ApiError::DatabaseConnectivityError => StatusCode::SERVICE_UNAVAILABLE,
             ApiError::ParseIdError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             ApiError::UserError(ref e) => match e {
-                UserError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-                UserError::UserAlreadyExists => StatusCode::CONFLICT,
-                UserError::UserNotFound => StatusCode::NOT_FOUND,
+                UserError::DatabaseError(_) | UserError::EncryptionError(_) => {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+                UserError::UserAlreadyExists
+                | UserError::AccountExists
+                | UserError::IdentityAlreadyLinked
+                | UserError::LastIdentity => StatusCode::CONFLICT,
+                UserError::UserNotFound
+                | UserError::IdentityNotFound
+                | UserError::RoleNotFound(_) => StatusCode::NOT_FOUND,
             },
             ApiError::PaymentError(ref e) => match e {
                 PaymentError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
@@ -81,10 +88,18 @@ impl ResponseError for ApiError {
                 PaymentError::PaymentNotFound => StatusCode::NOT_FOUND,
                 PaymentError::CreateCheckoutError => StatusCode::INTERNAL_SERVER_ERROR,
                 PaymentError::InvalidPaymentStatus(_) => StatusCode::BAD_REQUEST,
-                PaymentError::AllreadyHaveProduct => StatusCode::CONFLICT,
+                PaymentError::AllreadyHaveProduct | PaymentError::RecurringSubscriptionActive => {
+                    StatusCode::CONFLICT
+                }
+                PaymentError::InvalidWebhook(_) => StatusCode::BAD_REQUEST,
+                PaymentError::EventNotFound => StatusCode::NOT_FOUND,
+                PaymentError::EventAlreadyProcessed => StatusCode::CONFLICT,
             },
             ApiError::AuthError(ref e) => match e {
                 AuthError::AuthorizationFailed
+                | AuthError::InvalidCredentials
+                | AuthError::InvalidRefreshToken
+                | AuthError::TokenRevoked
                 | AuthError::InvalidTokenError(_)
                 | AuthError::JwtError(_)
                 | AuthError::JwtCreationFailed(_) => StatusCode::UNAUTHORIZED,
@@ -94,7 +109,35 @@ impl ResponseError for ApiError {
                 | AuthError::NetworkError(_)
                 | AuthError::SerdeParseError(_) => StatusCode::BAD_GATEWAY,
 
-                AuthError::InvalidCallbackData => StatusCode::BAD_REQUEST,
+                AuthError::InvalidCallbackData
+                | AuthError::InvalidOAuthState
+                | AuthError::InvalidEmail
+                | AuthError::InvalidPassword(_)
+                | AuthError::InvalidPasswordResetToken
+                | AuthError::InvalidEmailVerification
+                | AuthError::InvalidApiKeyRequest(_)
+                | AuthError::SelfImpersonation => StatusCode::BAD_REQUEST,
+
+                AuthError::InvalidMagicLink
+                | AuthError::InvalidMfaCode
+                | AuthError::InvalidApiKey => StatusCode::UNAUTHORIZED,
+
+                AuthError::MfaTooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
+
+                AuthError::MfaAlreadyEnabled => StatusCode::CONFLICT,
+
+                AuthError::EmailNotVerified | AuthError::AccountNotVerified => {
+                    StatusCode::FORBIDDEN
+                }
+
+                AuthError::UnknownProvider(_)
+                | AuthError::SessionNotFound
+                | AuthError::ApiKeyNotFound
+                | AuthError::MfaNotEnrolled => StatusCode::NOT_FOUND,
+
+                AuthError::DatabaseError(_)
+                | AuthError::EncryptionError(_)
+                | AuthError::PasswordHashError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             },
             ApiError::SubscriptionError(ref e) => match e {
                 SubscriptionError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
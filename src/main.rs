@@ -13,12 +13,12 @@ use crate::{
     modules::{
         auth::{
             self,
-            provider::{google, OAuthProvider},
+            provider::{github, google, oidc, ProviderRegistry},
         },
         stripe_payments, subscription,
         user::{self},
     },
-    utils::PostgresRepository,
+    utils::{mailer_from_config, Config, PostgresRepository},
 };
 
 #[actix_web::main]
@@ -26,9 +26,33 @@ async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "info,debug");
     env_logger::init();
 
+    let config = Config::from_env();
     let repo = Arc::new(PostgresRepository::new().await);
 
     let user_service = Arc::new(user::Service::new(repo.clone()));
+
+    let mailer = mailer_from_config(&config);
+    let auth_service = Arc::new(auth::Service::new(
+        repo.clone(),
+        user_service.clone(),
+        mailer.clone(),
+    ));
+
+    // One-off command, run after adding an encryption key: `<binary> reencrypt-tokens`
+    if std::env::args().nth(1).as_deref() == Some("reencrypt-tokens") {
+        let reencrypted = user_service
+            .reencrypt_oauth_refresh_tokens()
+            .await
+            .map_err(|err| std::io::Error::other(err.to_string()))?;
+        log::info!("Re-encrypted {} OAuth refresh tokens", reencrypted);
+        let reencrypted = auth_service
+            .reencrypt_mfa_secrets()
+            .await
+            .map_err(|err| std::io::Error::other(err.to_string()))?;
+        log::info!("Re-encrypted {} MFA secrets", reencrypted);
+        return Ok(());
+    }
+
     let subscription_service = Arc::new(modules::subscription::Service::new(repo.clone()));
     let payment_service = Arc::new(stripe_payments::Service::new(
         repo.clone(),
@@ -36,7 +60,21 @@ async fn main() -> std::io::Result<()> {
         subscription_service.clone(),
     ));
 
-    let oauth_google = Arc::new(google::Provider::new(user_service.clone()));
+    actix_web::rt::spawn(payment_service.clone().run_inbox_worker());
+
+    let mut oauth_providers = ProviderRegistry::new();
+    oauth_providers.register(Arc::new(google::Provider::new()));
+    if config.github_client_id.is_some() {
+        oauth_providers.register(Arc::new(github::Provider::new()));
+    }
+    for oidc_config in config.oidc_providers {
+        let name = oidc_config.name.clone();
+        let provider = oidc::Provider::discover(oidc_config)
+            .await
+            .unwrap_or_else(|err| panic!("OIDC discovery failed for '{}': {}", name, err));
+        oauth_providers.register(Arc::new(provider));
+    }
+    let oauth_providers = Arc::new(oauth_providers);
 
     log::info!("Starting HTTP server on 0.0.0.0:80...");
     HttpServer::new(move || {
@@ -52,9 +90,8 @@ async fn main() -> std::io::Result<()> {
             .app_data(web::Data::new(payment_service.clone()))
             .app_data(web::Data::new(subscription_service.clone()))
             .app_data(web::Data::new(user_service.clone()))
-            .app_data(web::Data::new(
-                oauth_google.clone() as Arc<dyn OAuthProvider>
-            ))
+            .app_data(web::Data::new(auth_service.clone()))
+            .app_data(web::Data::new(oauth_providers.clone()))
     })
     .bind("0.0.0.0:80")?
     .run()
@@ -0,0 +1,121 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::modules::{
+    auth::{AuditEvent, ImpersonationToken, LoginOutcome, MfaEnrollment, OAuthResponse},
+    user::api::UserProfile,
+};
+
+#[derive(Debug, Serialize)]
+pub struct AuthorizationUrlResponse {
+    pub url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub user: UserProfile,
+    pub token: String,
+    pub refresh_token: String,
+}
+
+/// Body of every login endpoint: the tokens, or what is needed to finish the
+/// login at `POST /auth/mfa/verify`.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Tokens(TokenResponse),
+    MfaRequired(MfaRequiredResponse),
+}
+
+#[derive(Debug, Serialize)]
+pub struct MfaRequiredResponse {
+    pub mfa_required: bool,
+    pub mfa_token: String,
+    pub expires_in: i64,
+}
+
+impl From<LoginOutcome> for LoginResponse {
+    fn from(outcome: LoginOutcome) -> Self {
+        match outcome {
+            LoginOutcome::Tokens(response) => LoginResponse::Tokens(response.into()),
+            LoginOutcome::MfaRequired {
+                mfa_token,
+                expires_in,
+            } => LoginResponse::MfaRequired(MfaRequiredResponse {
+                mfa_required: true,
+                mfa_token,
+                expires_in,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MfaEnrollmentResponse {
+    pub secret: String,
+    pub provisioning_uri: String,
+}
+
+impl From<MfaEnrollment> for MfaEnrollmentResponse {
+    fn from(enrollment: MfaEnrollment) -> Self {
+        MfaEnrollmentResponse {
+            secret: enrollment.secret,
+            provisioning_uri: enrollment.provisioning_uri,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+impl From<OAuthResponse> for TokenResponse {
+    fn from(response: OAuthResponse) -> Self {
+        TokenResponse {
+            user: response.user.into(),
+            token: response.token,
+            refresh_token: response.refresh_token,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImpersonationResponse {
+    pub token: String,
+    pub expires_in: i64,
+}
+
+impl From<ImpersonationToken> for ImpersonationResponse {
+    fn from(token: ImpersonationToken) -> Self {
+        ImpersonationResponse {
+            token: token.token,
+            expires_in: token.expires_in,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEventResponse {
+    pub id: i64,
+    pub actor_user_id: i32,
+    pub action: String,
+    pub target_user_id: Option<i32>,
+    pub reason: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<AuditEvent> for AuditEventResponse {
+    fn from(event: AuditEvent) -> Self {
+        AuditEventResponse {
+            id: event.id,
+            actor_user_id: event.actor_user_id,
+            action: event.action,
+            target_user_id: event.target_user_id,
+            reason: event.reason,
+            ip_address: event.ip_address,
+            created_at: event.created_at,
+        }
+    }
+}
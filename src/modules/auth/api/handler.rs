@@ -1,30 +1,351 @@
 use std::{collections::HashMap, sync::Arc};
 
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{
+    cookie::{time::Duration, Cookie, SameSite},
+    web, HttpRequest, HttpResponse,
+};
+use oauth2::PkceCodeVerifier;
 
 use crate::{
     error::ApiError,
-    modules::auth::{provider::OAuthProvider, AuthError},
+    modules::{
+        auth::{
+            provider::ProviderRegistry, AuditLogQuery, AuthError, ClientInfo,
+            EmailVerificationQuery, ForgotPasswordRequest, ImpersonateRequest, MagicLinkQuery,
+            MagicLinkRequest, MfaCodeRequest, MfaVerifyRequest, OAuthProviderType,
+            PasswordLoginRequest, ProviderInfo, RefreshRequest, RegisterRequest,
+            ResetPasswordRequest, Service,
+        },
+        user::api::IdentityResponse,
+    },
+    utils::extractor::AuthUser,
+};
+
+use super::{
+    AuditEventResponse, AuthorizationUrlResponse, ImpersonationResponse, LoginResponse,
+    MfaEnrollmentResponse, RecoveryCodesResponse, TokenResponse,
 };
 
-pub async fn redirect_to_oauth(oauth_manager: web::Data<Arc<dyn OAuthProvider>>) -> impl Responder {
-    let (url, _csrf_token) = oauth_manager.get_authorization_url().await;
-    HttpResponse::Found()
+const OAUTH_STATE_COOKIE: &str = "oauth_state";
+
+pub async fn get_providers(registry: web::Data<Arc<ProviderRegistry>>) -> HttpResponse {
+    let providers: Vec<ProviderInfo> = registry
+        .enabled()
+        .iter()
+        .map(|provider_type| ProviderInfo {
+            name: provider_type.as_str().to_string(),
+            redirect_url: format!("/auth/{}/redirect", provider_type.as_str()),
+        })
+        .collect();
+    HttpResponse::Ok().json(providers)
+}
+
+pub async fn redirect_to_oauth(
+    provider: web::Path<String>,
+    registry: web::Data<Arc<ProviderRegistry>>,
+    service: web::Data<Arc<Service>>,
+) -> Result<HttpResponse, ApiError> {
+    let (url, cookie) =
+        start_authorization(provider.into_inner(), &registry, &service, None).await?;
+
+    Ok(HttpResponse::Found()
         .append_header(("Location", url.as_str()))
-        .finish()
+        .cookie(cookie)
+        .finish())
+}
+
+/// Starts a login flow whose callback links the provider to the current user.
+/// It is called with the access token, so the URL is returned instead of redirecting.
+pub async fn link_provider(
+    user: AuthUser,
+    provider: web::Path<String>,
+    registry: web::Data<Arc<ProviderRegistry>>,
+    service: web::Data<Arc<Service>>,
+) -> Result<HttpResponse, ApiError> {
+    let (url, cookie) = start_authorization(
+        provider.into_inner(),
+        &registry,
+        &service,
+        Some(user.account_claims()?.sub),
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok()
+        .cookie(cookie)
+        .json(AuthorizationUrlResponse { url }))
 }
 
 pub async fn oauth_callback(
-    oauth_manager: web::Data<Arc<dyn OAuthProvider>>,
+    req: HttpRequest,
+    provider: web::Path<String>,
+    registry: web::Data<Arc<ProviderRegistry>>,
+    service: web::Data<Arc<Service>>,
     query: web::Query<HashMap<String, String>>,
 ) -> Result<HttpResponse, ApiError> {
+    let provider_type = OAuthProviderType::from(provider.into_inner());
+    let oauth_manager = registry.get(&provider_type)?;
+
+    let state = query.get("state").ok_or(AuthError::InvalidOAuthState)?;
+    let cookie_state = req.cookie(OAUTH_STATE_COOKIE);
+    let oauth_state = service
+        .verify_oauth_state(
+            &provider_type,
+            state,
+            cookie_state.as_ref().map(|c| c.value()),
+        )
+        .await?;
+
     if let Some(code) = query.get("code") {
-        let token = oauth_manager
-            .handle_oauth_callback(code.to_string())
+        let oauth_data = oauth_manager
+            .handle_oauth_callback(
+                code.to_string(),
+                PkceCodeVerifier::new(oauth_state.pkce_verifier),
+            )
             .await?;
-        Ok(HttpResponse::Ok().json(token))
+        let mut response = HttpResponse::Ok();
+        response.cookie(state_cookie(String::new(), Duration::ZERO));
+        match oauth_state.link_user_id {
+            Some(user_id) => {
+                let identity = service.link_identity(user_id, oauth_data).await?;
+                Ok(response.json(IdentityResponse::from(identity)))
+            }
+            None => {
+                let outcome = service
+                    .login(oauth_data, client_info(&req, &service))
+                    .await?;
+                Ok(response.json(LoginResponse::from(outcome)))
+            }
+        }
     } else {
         log::error!("Invalid callback data provided");
         Err(AuthError::InvalidCallbackData)?
     }
 }
+
+pub async fn refresh_token(
+    service: web::Data<Arc<Service>>,
+    body: web::Json<RefreshRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let token = service.refresh(&body.refresh_token).await?;
+    Ok(HttpResponse::Ok().json(TokenResponse::from(token)))
+}
+
+pub async fn register(
+    service: web::Data<Arc<Service>>,
+    body: web::Json<RegisterRequest>,
+) -> Result<HttpResponse, ApiError> {
+    service
+        .register(&body.email, &body.name, &body.password)
+        .await?;
+    Ok(HttpResponse::Accepted().finish())
+}
+
+pub async fn verify_email(
+    service: web::Data<Arc<Service>>,
+    query: web::Query<EmailVerificationQuery>,
+) -> Result<HttpResponse, ApiError> {
+    service.verify_email(&query.token).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn login_with_password(
+    req: HttpRequest,
+    service: web::Data<Arc<Service>>,
+    body: web::Json<PasswordLoginRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let outcome = service
+        .login_with_password(&body.email, &body.password, client_info(&req, &service))
+        .await?;
+    Ok(HttpResponse::Ok().json(LoginResponse::from(outcome)))
+}
+
+pub async fn forgot_password(
+    service: web::Data<Arc<Service>>,
+    body: web::Json<ForgotPasswordRequest>,
+) -> Result<HttpResponse, ApiError> {
+    service.request_password_reset(&body.email).await?;
+    Ok(HttpResponse::Accepted().finish())
+}
+
+pub async fn reset_password(
+    service: web::Data<Arc<Service>>,
+    body: web::Json<ResetPasswordRequest>,
+) -> Result<HttpResponse, ApiError> {
+    service.reset_password(&body.token, &body.password).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn send_magic_link(
+    service: web::Data<Arc<Service>>,
+    body: web::Json<MagicLinkRequest>,
+) -> Result<HttpResponse, ApiError> {
+    service.send_magic_link(&body.email).await?;
+    Ok(HttpResponse::Accepted().finish())
+}
+
+pub async fn verify_magic_link(
+    req: HttpRequest,
+    service: web::Data<Arc<Service>>,
+    query: web::Query<MagicLinkQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let outcome = service
+        .verify_magic_link(&query.token, client_info(&req, &service))
+        .await?;
+    Ok(HttpResponse::Ok().json(LoginResponse::from(outcome)))
+}
+
+pub async fn enroll_mfa(
+    user: AuthUser,
+    service: web::Data<Arc<Service>>,
+) -> Result<HttpResponse, ApiError> {
+    let enrollment = service.enroll_mfa(user.account_claims()?.sub).await?;
+    Ok(HttpResponse::Ok().json(MfaEnrollmentResponse::from(enrollment)))
+}
+
+pub async fn enable_mfa(
+    user: AuthUser,
+    service: web::Data<Arc<Service>>,
+    body: web::Json<MfaCodeRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let recovery_codes = service
+        .enable_mfa(user.account_claims()?.sub, &body.code)
+        .await?;
+    Ok(HttpResponse::Ok().json(RecoveryCodesResponse { recovery_codes }))
+}
+
+pub async fn disable_mfa(
+    user: AuthUser,
+    service: web::Data<Arc<Service>>,
+    body: web::Json<MfaCodeRequest>,
+) -> Result<HttpResponse, ApiError> {
+    service
+        .disable_mfa(user.account_claims()?.sub, &body.code)
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn verify_mfa(
+    req: HttpRequest,
+    service: web::Data<Arc<Service>>,
+    body: web::Json<MfaVerifyRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let token = service
+        .verify_mfa(&body.mfa_token, &body.code, client_info(&req, &service))
+        .await?;
+    Ok(HttpResponse::Ok().json(TokenResponse::from(token)))
+}
+
+pub async fn logout(
+    user: AuthUser,
+    service: web::Data<Arc<Service>>,
+) -> Result<HttpResponse, ApiError> {
+    service.logout(user.account_claims()?).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn logout_all(
+    user: AuthUser,
+    service: web::Data<Arc<Service>>,
+) -> Result<HttpResponse, ApiError> {
+    service.logout_all(user.account_claims()?.sub).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn impersonate(
+    req: HttpRequest,
+    user: AuthUser,
+    user_id: web::Path<i32>,
+    service: web::Data<Arc<Service>>,
+    body: Option<web::Json<ImpersonateRequest>>,
+) -> Result<HttpResponse, ApiError> {
+    let reason = body.and_then(|body| body.into_inner().reason);
+    let token = service
+        .impersonate(
+            user.account_claims()?,
+            user_id.into_inner(),
+            reason,
+            client_info(&req, &service).ip_address,
+        )
+        .await?;
+    Ok(HttpResponse::Ok().json(ImpersonationResponse::from(token)))
+}
+
+pub async fn get_audit_log(
+    service: web::Data<Arc<Service>>,
+    query: web::Query<AuditLogQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let events: Vec<AuditEventResponse> = service
+        .get_audit_events(
+            query.target_user_id,
+            query.before,
+            query.limit.unwrap_or(50),
+        )
+        .await?
+        .into_iter()
+        .map(AuditEventResponse::from)
+        .collect();
+    Ok(HttpResponse::Ok().json(events))
+}
+
+pub async fn get_jwks(service: web::Data<Arc<Service>>) -> HttpResponse {
+    HttpResponse::Ok().json(service.jwks())
+}
+
+/// Stores the state and PKCE verifier of a new provider login and returns the
+/// provider's authorization URL with the cookie binding the state to the browser.
+async fn start_authorization(
+    provider: String,
+    registry: &ProviderRegistry,
+    service: &Service,
+    link_user_id: Option<i32>,
+) -> Result<(String, Cookie<'static>), ApiError> {
+    let provider_type = OAuthProviderType::from(provider);
+    let oauth_manager = registry.get(&provider_type)?;
+
+    let authorization = oauth_manager.get_authorization_url().await;
+    let oauth_state = service
+        .create_oauth_state(
+            &provider_type,
+            authorization.csrf_token.secret(),
+            authorization.pkce_verifier.secret(),
+            link_user_id,
+        )
+        .await?;
+
+    let cookie = state_cookie(
+        oauth_state.state,
+        Duration::seconds((oauth_state.expires_at - oauth_state.created_at).num_seconds()),
+    );
+    Ok((authorization.url, cookie))
+}
+
+/// Takes the client IP from the forwarded headers only when a trusted proxy sent
+/// them, anyone else could put any address there.
+fn client_info(req: &HttpRequest, service: &Service) -> ClientInfo {
+    let peer_ip = req.peer_addr().map(|addr| addr.ip());
+    let ip_address = match peer_ip {
+        Some(ip) if service.is_trusted_proxy(ip) => req
+            .connection_info()
+            .realip_remote_addr()
+            .map(|addr| addr.to_string()),
+        _ => peer_ip.map(|ip| ip.to_string()),
+    };
+    ClientInfo {
+        user_agent: req
+            .headers()
+            .get("User-Agent")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string()),
+        ip_address,
+    }
+}
+
+fn state_cookie(value: String, max_age: Duration) -> Cookie<'static> {
+    Cookie::build(OAUTH_STATE_COOKIE, value)
+        .path("/auth")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .max_age(max_age)
+        .finish()
+}
@@ -1,3 +1,6 @@
+mod dto;
+pub use dto::*;
+
 mod handler;
 pub use handler::*;
 
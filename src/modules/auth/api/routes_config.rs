@@ -1,11 +1,47 @@
 use actix_web::web;
+use actix_web_lab::middleware::from_fn;
 
-use crate::modules::auth::api::{oauth_callback, redirect_to_oauth};
+use crate::{
+    modules::auth::api::{
+        disable_mfa, enable_mfa, enroll_mfa, forgot_password, get_audit_log, get_jwks,
+        get_providers, impersonate, link_provider, login_with_password, logout, logout_all,
+        oauth_callback, redirect_to_oauth, refresh_token, register, reset_password,
+        send_magic_link, verify_email, verify_magic_link, verify_mfa,
+    },
+    utils::middleware::require_role,
+};
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/auth")
-            .route("/redirect", web::get().to(redirect_to_oauth))
-            .route("/callback", web::get().to(oauth_callback)),
-    );
+            .route("/providers", web::get().to(get_providers))
+            .route("/refresh", web::post().to(refresh_token))
+            .route("/register", web::post().to(register))
+            .route("/email/verify", web::get().to(verify_email))
+            .route("/login", web::post().to(login_with_password))
+            .route("/password/forgot", web::post().to(forgot_password))
+            .route("/password/reset", web::post().to(reset_password))
+            .route("/magic-link", web::post().to(send_magic_link))
+            .route("/magic-link/verify", web::get().to(verify_magic_link))
+            .route("/mfa/verify", web::post().to(verify_mfa))
+            .route("/mfa/enroll", web::post().to(enroll_mfa))
+            .route("/mfa/enable", web::post().to(enable_mfa))
+            .route("/mfa/disable", web::post().to(disable_mfa))
+            .route("/logout", web::post().to(logout))
+            .route("/logout-all", web::post().to(logout_all))
+            .route("/{provider}/redirect", web::get().to(redirect_to_oauth))
+            .route("/{provider}/link", web::post().to(link_provider))
+            .route("/{provider}/callback", web::get().to(oauth_callback)),
+    )
+    .service(
+        web::resource("/admin/users/{user_id}/impersonate")
+            .route(web::post().to(impersonate))
+            .wrap(from_fn(require_role("admin"))),
+    )
+    .service(
+        web::resource("/admin/audit-log")
+            .route(web::get().to(get_audit_log))
+            .wrap(from_fn(require_role("admin"))),
+    )
+    .service(web::resource("/.well-known/jwks.json").route(web::get().to(get_jwks)));
 }
@@ -0,0 +1,1382 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use chrono::{DateTime, TimeZone, Utc};
+use jsonwebtoken::jwk::JwkSet;
+use uuid::Uuid;
+
+use crate::{
+    error::ApiError,
+    modules::user::{self, Identity, User, UserError},
+    utils::{is_valid_email, Config, Email, Mailer, TtlCache},
+};
+
+use super::{
+    api_key_prefix, generate_recovery_codes, generate_totp_secret, hash_password,
+    hash_recovery_code, hash_token, ports::Repository, totp_provisioning_uri, validate_password,
+    verify_password, verify_totp, ApiKey, AuditEvent, AuthError, Claims, ClientInfo,
+    ImpersonationToken, JwtKeys, LoginOutcome, MagicLinkClaims, MfaEnrollment, MfaSettings,
+    OAuthData, OAuthProviderType, OAuthResponse, OAuthState, PasswordResetToken, RefreshToken,
+    Session, AUDIT_IMPERSONATION_STARTED,
+};
+
+/// How long another instance may keep accepting a token after it was revoked. It is
+/// also how often the `last_seen_at` of a session gets refreshed.
+const REVOCATION_CACHE_TTL: Duration = Duration::from_secs(30);
+/// Most audit events returned per page.
+const MAX_AUDIT_EVENTS: i64 = 100;
+/// Codes checked per user within `MFA_ATTEMPT_WINDOW_MINUTES` before the second
+/// factor locks until the window ends. A correct code starts the count over.
+const MAX_MFA_ATTEMPTS: i32 = 5;
+const MFA_ATTEMPT_WINDOW_MINUTES: i64 = 15;
+
+pub struct Service {
+    repository: Arc<dyn Repository>,
+    user_service: Arc<user::Service>,
+    mailer: Arc<dyn Mailer>,
+    config: Config,
+    jwt_keys: JwtKeys,
+    revoked_tokens: TtlCache<String, bool>,
+    revoked_sessions: TtlCache<Uuid, bool>,
+    user_tokens_revoked_before: TtlCache<i32, Option<DateTime<Utc>>>,
+}
+
+impl Service {
+    pub fn new(
+        repository: Arc<dyn Repository>,
+        user_service: Arc<user::Service>,
+        mailer: Arc<dyn Mailer>,
+    ) -> Self {
+        let config = Config::from_env();
+        Self {
+            repository,
+            user_service,
+            mailer,
+            jwt_keys: JwtKeys::from_config(&config),
+            config,
+            revoked_tokens: TtlCache::new(REVOCATION_CACHE_TTL),
+            revoked_sessions: TtlCache::new(REVOCATION_CACHE_TTL),
+            user_tokens_revoked_before: TtlCache::new(REVOCATION_CACHE_TTL),
+        }
+    }
+}
+
+impl Service {
+    /// Whether the forwarded headers of a request from `ip` tell the client address.
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.config.trusted_proxies.contains(&ip)
+    }
+}
+
+//OAuth state
+impl Service {
+    pub async fn create_oauth_state(
+        &self,
+        provider: &OAuthProviderType,
+        state: &str,
+        pkce_verifier: &str,
+        link_user_id: Option<i32>,
+    ) -> Result<OAuthState, ApiError> {
+        self.repository.delete_expired_oauth_states().await?;
+        Ok(self
+            .repository
+            .create_oauth_state(&OAuthState::new(
+                provider,
+                state,
+                pkce_verifier,
+                link_user_id,
+            ))
+            .await?)
+    }
+
+    /// Checks the `state` returned by the provider against the one bound to the
+    /// browser and consumes it, so every state can only be used once.
+    pub async fn verify_oauth_state(
+        &self,
+        provider: &OAuthProviderType,
+        state: &str,
+        cookie_state: Option<&str>,
+    ) -> Result<OAuthState, ApiError> {
+        if cookie_state != Some(state) {
+            log::warn!("OAuth state does not match the state cookie");
+            return Err(AuthError::InvalidOAuthState)?;
+        }
+
+        let oauth_state = self
+            .repository
+            .take_oauth_state(state)
+            .await?
+            .ok_or(AuthError::InvalidOAuthState)?;
+
+        if oauth_state.is_expired() || oauth_state.provider != provider.as_str() {
+            return Err(AuthError::InvalidOAuthState)?;
+        }
+
+        Ok(oauth_state)
+    }
+}
+
+//Tokens
+impl Service {
+    pub async fn login(
+        &self,
+        oauth_data: OAuthData,
+        client_info: ClientInfo,
+    ) -> Result<LoginOutcome, ApiError> {
+        let user = self.user_service.sign_up_or_login(oauth_data).await?;
+        self.start_session(user, client_info).await
+    }
+
+    pub async fn link_identity(
+        &self,
+        user_id: i32,
+        oauth_data: OAuthData,
+    ) -> Result<Identity, ApiError> {
+        self.user_service.link_identity(user_id, oauth_data).await
+    }
+
+    /// Exchanges a refresh token for a new access/refresh token pair. Presenting a
+    /// token that was already exchanged means it leaked, so its whole session is revoked.
+    pub async fn refresh(&self, token: &str) -> Result<OAuthResponse, ApiError> {
+        let refresh_token = self
+            .repository
+            .get_refresh_token_by_hash(&hash_token(token))
+            .await?
+            .ok_or(AuthError::InvalidRefreshToken)?;
+
+        if refresh_token.is_expired() && !refresh_token.is_spent() {
+            return Err(AuthError::InvalidRefreshToken)?;
+        }
+
+        // A concurrent refresh with the same token loses the race and counts as reuse too
+        if refresh_token.is_spent()
+            || !self
+                .repository
+                .rotate_refresh_token(refresh_token.id)
+                .await?
+        {
+            log::warn!(
+                "Refresh token reuse detected for user {}, revoking session {}",
+                refresh_token.user_id,
+                refresh_token.family_id
+            );
+            self.revoke_session(refresh_token.family_id).await?;
+            return Err(AuthError::InvalidRefreshToken)?;
+        }
+
+        let user = self
+            .user_service
+            .get_user_by_id(refresh_token.user_id)
+            .await?
+            .ok_or(UserError::UserNotFound)?;
+        self.issue_tokens(user, refresh_token.family_id).await
+    }
+
+    /// Every login ends here: users with a second factor get an MFA pending token
+    /// to redeem at `verify_mfa` instead of a session.
+    async fn start_session(
+        &self,
+        user: User,
+        client_info: ClientInfo,
+    ) -> Result<LoginOutcome, ApiError> {
+        let mfa = self.repository.get_mfa(user.id).await?;
+        if mfa.is_some_and(|mfa| mfa.is_enabled()) {
+            let expiration_minutes = self.config.mfa_pending_expiration_minutes;
+            let mfa_token = self
+                .jwt_keys
+                .create_mfa_pending_token(user.id, expiration_minutes)?;
+            return Ok(LoginOutcome::MfaRequired {
+                mfa_token,
+                expires_in: expiration_minutes * 60,
+            });
+        }
+
+        Ok(LoginOutcome::Tokens(
+            self.create_session(user, client_info).await?,
+        ))
+    }
+
+    async fn create_session(
+        &self,
+        user: User,
+        client_info: ClientInfo,
+    ) -> Result<OAuthResponse, ApiError> {
+        let session = self
+            .repository
+            .create_session(&Session::new(user.id, client_info))
+            .await?;
+        self.issue_tokens(user, session.id).await
+    }
+
+    async fn issue_tokens(&self, user: User, session_id: Uuid) -> Result<OAuthResponse, ApiError> {
+        let roles = self.user_service.get_user_roles(user.id).await?;
+        let token = self.jwt_keys.create_jwt(&user, &roles, session_id)?;
+        let (refresh_token, stored_refresh_token) = RefreshToken::generate(
+            user.id,
+            session_id,
+            self.config.refresh_token_expiration_days,
+        );
+        self.repository
+            .create_refresh_token(&stored_refresh_token)
+            .await?;
+
+        Ok(OAuthResponse {
+            user,
+            token,
+            refresh_token,
+        })
+    }
+}
+
+//Revocation
+impl Service {
+    /// Verifies an access token and makes sure it was not revoked since it was issued.
+    pub async fn authenticate(&self, token: &str) -> Result<Claims, ApiError> {
+        let claims = self.jwt_keys.verify_jwt(token)?;
+        if self.is_revoked(&claims).await? {
+            return Err(AuthError::TokenRevoked)?;
+        }
+        Ok(claims)
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        self.jwt_keys.jwks()
+    }
+
+    /// Revokes the access token and the session it belongs to.
+    pub async fn logout(&self, claims: &Claims) -> Result<(), ApiError> {
+        let expires_at = Utc
+            .timestamp_opt(claims.exp, 0)
+            .single()
+            .unwrap_or_else(Utc::now);
+        self.repository.delete_expired_revoked_tokens().await?;
+        self.repository
+            .revoke_token(&claims.jti, claims.sub, expires_at)
+            .await?;
+        self.revoke_session(claims.sid).await?;
+        self.revoked_tokens.insert(claims.jti.clone(), true).await;
+        Ok(())
+    }
+
+    pub async fn logout_all(&self, user_id: i32) -> Result<(), ApiError> {
+        let revoked_before = Utc::now();
+        self.repository
+            .revoke_all_user_tokens(user_id, revoked_before)
+            .await?;
+        self.user_tokens_revoked_before
+            .insert(user_id, Some(revoked_before))
+            .await;
+        Ok(())
+    }
+
+    async fn is_revoked(&self, claims: &Claims) -> Result<bool, ApiError> {
+        let revoked = match self.revoked_tokens.get(&claims.jti).await {
+            Some(revoked) => revoked,
+            None => {
+                let revoked = self.repository.is_token_revoked(&claims.jti).await?;
+                self.revoked_tokens
+                    .insert(claims.jti.clone(), revoked)
+                    .await;
+                revoked
+            }
+        };
+        if revoked {
+            return Ok(true);
+        }
+
+        let session_revoked = match self.revoked_sessions.get(&claims.sid).await {
+            Some(revoked) => revoked,
+            None => {
+                let revoked = self
+                    .repository
+                    .touch_session(claims.sid)
+                    .await?
+                    .is_none_or(|session| session.revoked_at.is_some());
+                self.revoked_sessions.insert(claims.sid, revoked).await;
+                revoked
+            }
+        };
+        if session_revoked {
+            return Ok(true);
+        }
+
+        let revoked_before = match self.user_tokens_revoked_before.get(&claims.sub).await {
+            Some(revoked_before) => revoked_before,
+            None => {
+                let revoked_before = self
+                    .repository
+                    .get_user_tokens_revoked_before(claims.sub)
+                    .await?;
+                self.user_tokens_revoked_before
+                    .insert(claims.sub, revoked_before)
+                    .await;
+                revoked_before
+            }
+        };
+        // `iat` only has second precision: tokens from the same second as the revocation
+        // are kept, so a login right after it works, older ones die with their session
+        Ok(revoked_before.is_some_and(|revoked_before| claims.iat < revoked_before.timestamp()))
+    }
+}
+
+//Sessions
+impl Service {
+    pub async fn get_sessions(&self, user_id: i32) -> Result<Vec<Session>, ApiError> {
+        Ok(self.repository.get_active_sessions_by_user(user_id).await?)
+    }
+
+    /// Revokes a session of the user, its access and refresh tokens stop working.
+    pub async fn revoke_user_session(
+        &self,
+        user_id: i32,
+        session_id: Uuid,
+    ) -> Result<(), ApiError> {
+        match self.repository.get_session(session_id).await? {
+            Some(session) if session.user_id == user_id => self.revoke_session(session.id).await,
+            _ => Err(AuthError::SessionNotFound)?,
+        }
+    }
+
+    async fn revoke_session(&self, session_id: Uuid) -> Result<(), ApiError> {
+        self.repository.revoke_session(session_id).await?;
+        self.revoked_sessions.insert(session_id, true).await;
+        Ok(())
+    }
+}
+
+//Passwords
+impl Service {
+    /// Signs up an unverified user and mails them a verification link. It answers
+    /// the same for an email that already has an account, whose owner gets a
+    /// password reset link instead, so the endpoint does not tell who has one.
+    pub async fn register(&self, email: &str, name: &str, password: &str) -> Result<(), ApiError> {
+        let email = email.trim();
+        if !is_valid_email(email) {
+            return Err(AuthError::InvalidEmail)?;
+        }
+        validate_password(password)?;
+
+        // Hash before looking the email up, so both cases take as long.
+        let password_hash = run_blocking(hash_password, password).await??;
+        if let Some(user) = self.user_service.get_user_by_email(email).await? {
+            return self.send_account_exists_email(user).await;
+        }
+        let user = match self
+            .user_service
+            .sign_up_with_password(email, name.trim(), &password_hash)
+            .await
+        {
+            Ok(user) => user,
+            Err(ApiError::UserError(UserError::UserAlreadyExists)) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        let expiration_minutes = self.config.email_verification_expiration_minutes;
+        let token = self.jwt_keys.create_email_verification_token(
+            user.id,
+            &user.email,
+            expiration_minutes,
+        )?;
+        let email = Email {
+            to: user.email,
+            subject: "Verify your email".to_string(),
+            body: format!(
+                "Use this link to confirm your email and finish signing up, it expires in {} minutes:\n{}?token={}\nIf you did not sign up, ignore this email.",
+                expiration_minutes, self.config.email_verification_url, token
+            ),
+        };
+        if let Err(err) = self.mailer.send(email).await {
+            log::error!(
+                "Could not send verification email to user {}: {}",
+                user.id,
+                err
+            );
+        }
+        Ok(())
+    }
+
+    /// Confirms the email of a password sign up, as long as it did not change since
+    /// the link was mailed.
+    pub async fn verify_email(&self, token: &str) -> Result<(), ApiError> {
+        let claims = self
+            .jwt_keys
+            .verify_email_verification_token(token)
+            .map_err(|_| AuthError::InvalidEmailVerification)?;
+        match self.user_service.get_user_by_id(claims.sub).await? {
+            Some(user) if user.email == claims.email => {
+                self.user_service.verify_email(user).await?;
+                Ok(())
+            }
+            _ => Err(AuthError::InvalidEmailVerification)?,
+        }
+    }
+
+    /// Tells the owner of an account someone tried to sign up with their email,
+    /// with a reset link in case it was them. Redeeming it also verifies the email.
+    async fn send_account_exists_email(&self, user: User) -> Result<(), ApiError> {
+        let token = self.create_password_reset_token(user.id).await?;
+        let email = Email {
+            to: user.email,
+            subject: "You already have an account".to_string(),
+            body: format!(
+                "Someone tried to sign up with this email. If it was you, use this link to choose a password, it expires in {} minutes:\n{}?token={}\nOtherwise you can ignore this email.",
+                self.config.password_reset_expiration_minutes, self.config.password_reset_url, token
+            ),
+        };
+        if let Err(err) = self.mailer.send(email).await {
+            log::error!(
+                "Could not send account exists email to user {}: {}",
+                user.id,
+                err
+            );
+        }
+        Ok(())
+    }
+
+    pub async fn login_with_password(
+        &self,
+        email: &str,
+        password: &str,
+        client_info: ClientInfo,
+    ) -> Result<LoginOutcome, ApiError> {
+        let user = self.user_service.get_user_by_email(email.trim()).await?;
+        let password_hash = match &user {
+            Some(user) => self.user_service.get_password_hash(user.id).await?,
+            None => None,
+        };
+
+        let verified = run_blocking(
+            move |password| verify_password(password_hash.as_deref(), password),
+            password,
+        )
+        .await?;
+        match user {
+            Some(user) if verified && !user.email_verified => Err(AuthError::AccountNotVerified)?,
+            Some(user) if verified => self.start_session(user, client_info).await,
+            _ => Err(AuthError::InvalidCredentials)?,
+        }
+    }
+
+    /// Mails a one-time reset link. Unknown emails are ignored silently, so the
+    /// endpoint does not tell who has an account.
+    pub async fn request_password_reset(&self, email: &str) -> Result<(), ApiError> {
+        self.repository
+            .delete_expired_password_reset_tokens()
+            .await?;
+        let Some(user) = self.user_service.get_user_by_email(email.trim()).await? else {
+            return Ok(());
+        };
+
+        let token = self.create_password_reset_token(user.id).await?;
+
+        let email =
+            Email {
+                to: user.email,
+                subject: "Reset your password".to_string(),
+                body: format!(
+                "Use this link to choose a new password, it expires in {} minutes:\n{}?token={}",
+                self.config.password_reset_expiration_minutes, self.config.password_reset_url, token
+            ),
+            };
+        if let Err(err) = self.mailer.send(email).await {
+            log::error!(
+                "Could not send password reset email to user {}: {}",
+                user.id,
+                err
+            );
+        }
+        Ok(())
+    }
+
+    async fn create_password_reset_token(&self, user_id: i32) -> Result<String, ApiError> {
+        let (token, reset_token) =
+            PasswordResetToken::generate(user_id, self.config.password_reset_expiration_minutes);
+        self.repository
+            .create_password_reset_token(&reset_token)
+            .await?;
+        Ok(token)
+    }
+
+    /// Sets the new password and logs the user out everywhere. Redeeming the
+    /// mailed token also proves the user owns the email, verifying it unlinks the
+    /// identities of whoever claimed the address before.
+    pub async fn reset_password(&self, token: &str, password: &str) -> Result<(), ApiError> {
+        validate_password(password)?;
+        let reset_token = self
+            .repository
+            .take_password_reset_token(&hash_token(token))
+            .await?
+            .ok_or(AuthError::InvalidPasswordResetToken)?;
+        if reset_token.is_expired() {
+            return Err(AuthError::InvalidPasswordResetToken)?;
+        }
+
+        let user = self
+            .user_service
+            .get_user_by_id(reset_token.user_id)
+            .await?
+            .ok_or(UserError::UserNotFound)?;
+        self.user_service.verify_email(user).await?;
+
+        let password_hash = run_blocking(hash_password, password).await??;
+        self.user_service
+            .set_password_hash(reset_token.user_id, &password_hash)
+            .await?;
+        self.logout_all(reset_token.user_id).await
+    }
+}
+
+//Magic links
+impl Service {
+    /// Mails a link that logs in, or signs up, whoever can read the mailbox.
+    pub async fn send_magic_link(&self, email: &str) -> Result<(), ApiError> {
+        let email = email.trim();
+        if !is_valid_email(email) {
+            return Err(AuthError::InvalidEmail)?;
+        }
+
+        let expiration_minutes = self.config.magic_link_expiration_minutes;
+        let token = self
+            .jwt_keys
+            .create_magic_link_token(email, expiration_minutes)?;
+        let email = Email {
+            to: email.to_string(),
+            subject: "Your login link".to_string(),
+            body: format!(
+                "Use this link to log in, it expires in {} minutes and works once:\n{}?token={}",
+                expiration_minutes, self.config.magic_link_url, token
+            ),
+        };
+        if let Err(err) = self.mailer.send(email).await {
+            log::error!("Could not send magic link email: {}", err);
+        }
+        Ok(())
+    }
+
+    pub async fn verify_magic_link(
+        &self,
+        token: &str,
+        client_info: ClientInfo,
+    ) -> Result<LoginOutcome, ApiError> {
+        let claims: MagicLinkClaims = self
+            .jwt_keys
+            .verify_magic_link_token(token)
+            .map_err(|_| AuthError::InvalidMagicLink)?;
+
+        self.repository.delete_expired_magic_links().await?;
+        let expires_at = Utc
+            .timestamp_opt(claims.exp, 0)
+            .single()
+            .unwrap_or_else(Utc::now);
+        if !self
+            .repository
+            .use_magic_link(&claims.jti, expires_at)
+            .await?
+        {
+            return Err(AuthError::InvalidMagicLink)?;
+        }
+
+        let unverified_user = self
+            .user_service
+            .get_user_by_email(&claims.email)
+            .await?
+            .filter(|user| !user.email_verified);
+        let user = self
+            .user_service
+            .sign_up_or_login_with_email(&claims.email)
+            .await?;
+        // Whoever created an unverified account with this email never proved owning
+        // it, so their sessions end once their password and identities are gone
+        if let Some(unverified_user) = unverified_user {
+            self.logout_all(unverified_user.id).await?;
+        }
+        self.start_session(user, client_info).await
+    }
+}
+
+//Two-factor authentication
+impl Service {
+    /// Starts, or restarts, the TOTP enrollment. It only takes effect once
+    /// `enable_mfa` confirmed the authenticator app produces valid codes.
+    pub async fn enroll_mfa(&self, user_id: i32) -> Result<MfaEnrollment, ApiError> {
+        if let Some(mfa) = self.repository.get_mfa(user_id).await? {
+            if mfa.is_enabled() {
+                return Err(AuthError::MfaAlreadyEnabled)?;
+            }
+        }
+        let user = self
+            .user_service
+            .get_user_by_id(user_id)
+            .await?
+            .ok_or(UserError::UserNotFound)?;
+
+        let secret = generate_totp_secret();
+        self.repository.save_mfa_secret(user_id, &secret).await?;
+        Ok(MfaEnrollment {
+            provisioning_uri: totp_provisioning_uri(&secret, &self.config.mfa_issuer, &user.email),
+            secret,
+        })
+    }
+
+    /// Enables MFA with the first code of the enrolled secret and returns the
+    /// recovery codes, which are only shown this once.
+    pub async fn enable_mfa(&self, user_id: i32, code: &str) -> Result<Vec<String>, ApiError> {
+        let mfa = self
+            .repository
+            .get_mfa(user_id)
+            .await?
+            .ok_or(AuthError::MfaNotEnrolled)?;
+        if mfa.is_enabled() {
+            return Err(AuthError::MfaAlreadyEnabled)?;
+        }
+        let step = verify_totp(&mfa.totp_secret, code, Utc::now(), mfa.last_used_step)
+            .ok_or(AuthError::InvalidMfaCode)?;
+
+        let (codes, code_hashes): (Vec<String>, Vec<String>) =
+            generate_recovery_codes().into_iter().unzip();
+        self.repository
+            .enable_mfa(user_id, step, &code_hashes)
+            .await?;
+        Ok(codes)
+    }
+
+    pub async fn disable_mfa(&self, user_id: i32, code: &str) -> Result<(), ApiError> {
+        let mfa = self
+            .repository
+            .get_mfa(user_id)
+            .await?
+            .filter(MfaSettings::is_enabled)
+            .ok_or(AuthError::MfaNotEnrolled)?;
+        if !self.check_mfa_code(&mfa, code).await? {
+            return Err(AuthError::InvalidMfaCode)?;
+        }
+        Ok(self.repository.disable_mfa(user_id).await?)
+    }
+
+    pub async fn reencrypt_mfa_secrets(&self) -> Result<u64, ApiError> {
+        Ok(self.repository.reencrypt_mfa_secrets().await?)
+    }
+
+    /// Finishes a login that returned an MFA pending token.
+    pub async fn verify_mfa(
+        &self,
+        mfa_token: &str,
+        code: &str,
+        client_info: ClientInfo,
+    ) -> Result<OAuthResponse, ApiError> {
+        let claims = self.jwt_keys.verify_mfa_pending_token(mfa_token)?;
+
+        // MFA disabled since the login started leaves nothing to check the code against
+        let mfa = self
+            .repository
+            .get_mfa(claims.sub)
+            .await?
+            .filter(MfaSettings::is_enabled)
+            .ok_or(AuthError::InvalidMfaCode)?;
+        if !self.check_mfa_code(&mfa, code).await? {
+            return Err(AuthError::InvalidMfaCode)?;
+        }
+
+        // Burns the token, so it cannot start a second session
+        self.repository.delete_expired_mfa_pending_tokens().await?;
+        let expires_at = Utc
+            .timestamp_opt(claims.exp, 0)
+            .single()
+            .unwrap_or_else(Utc::now);
+        if !self
+            .repository
+            .use_mfa_pending_token(&claims.jti, expires_at)
+            .await?
+        {
+            return Err(AuthError::InvalidTokenError(
+                "MFA token already used".to_string(),
+            ))?;
+        }
+
+        let user = self
+            .user_service
+            .get_user_by_id(claims.sub)
+            .await?
+            .ok_or(UserError::UserNotFound)?;
+        self.create_session(user, client_info).await
+    }
+
+    /// Accepts a TOTP code newer than the last used one, or an unused recovery code.
+    /// Every check counts against the user's attempts, so concurrent guesses cannot
+    /// get past the limit either.
+    async fn check_mfa_code(&self, mfa: &MfaSettings, code: &str) -> Result<bool, ApiError> {
+        let window_start = Utc::now() - chrono::Duration::minutes(MFA_ATTEMPT_WINDOW_MINUTES);
+        let attempts = self
+            .repository
+            .record_mfa_attempt(mfa.user_id, window_start)
+            .await?
+            .unwrap_or(0);
+        if attempts > MAX_MFA_ATTEMPTS {
+            return Err(AuthError::MfaTooManyAttempts)?;
+        }
+
+        let valid = match verify_totp(&mfa.totp_secret, code, Utc::now(), mfa.last_used_step) {
+            Some(step) => self.repository.use_totp_step(mfa.user_id, step).await?,
+            None => {
+                self.repository
+                    .use_recovery_code(mfa.user_id, &hash_recovery_code(code))
+                    .await?
+            }
+        };
+        if valid {
+            self.repository.clear_mfa_attempts(mfa.user_id).await?;
+        }
+        Ok(valid)
+    }
+}
+
+//API keys
+impl Service {
+    /// Returns the key, which cannot be shown again, with what is stored of it.
+    pub async fn create_api_key(
+        &self,
+        user_id: i32,
+        name: &str,
+        scopes: Vec<String>,
+        expires_in_days: Option<i64>,
+    ) -> Result<(String, ApiKey), ApiError> {
+        let (key, api_key) = ApiKey::generate(user_id, name, scopes, expires_in_days)?;
+        let api_key = self.repository.create_api_key(&api_key).await?;
+        Ok((key, api_key))
+    }
+
+    pub async fn get_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>, ApiError> {
+        Ok(self.repository.get_api_keys_by_user(user_id).await?)
+    }
+
+    pub async fn revoke_api_key(&self, user_id: i32, id: Uuid) -> Result<(), ApiError> {
+        if !self.repository.revoke_api_key(id, user_id).await? {
+            return Err(AuthError::ApiKeyNotFound)?;
+        }
+        Ok(())
+    }
+
+    /// Accepts an API key in place of an access token.
+    pub async fn authenticate_api_key(&self, key: &str) -> Result<ApiKey, ApiError> {
+        let prefix = api_key_prefix(key).ok_or(AuthError::InvalidApiKey)?;
+        let api_key = self
+            .repository
+            .get_api_key_by_prefix(prefix)
+            .await?
+            .filter(|api_key| api_key.is_valid(key))
+            .ok_or(AuthError::InvalidApiKey)?;
+        self.repository.touch_api_key(api_key.id).await?;
+        Ok(api_key)
+    }
+}
+
+//Impersonation
+impl Service {
+    /// Issues a short lived access token of the user for the admin, without refresh
+    /// token. Every impersonation is written to the audit log first.
+    pub async fn impersonate(
+        &self,
+        admin: &Claims,
+        user_id: i32,
+        reason: Option<String>,
+        ip_address: Option<String>,
+    ) -> Result<ImpersonationToken, ApiError> {
+        if admin.sub == user_id {
+            return Err(AuthError::SelfImpersonation)?;
+        }
+        let user = self
+            .user_service
+            .get_user_by_id(user_id)
+            .await?
+            .ok_or(UserError::UserNotFound)?;
+
+        self.repository
+            .create_audit_event(&AuditEvent {
+                id: 0,
+                actor_user_id: admin.sub,
+                action: AUDIT_IMPERSONATION_STARTED.to_string(),
+                target_user_id: Some(user.id),
+                reason,
+                ip_address,
+                created_at: Utc::now(),
+            })
+            .await?;
+        log::info!("Admin {} impersonates user {}", admin.sub, user.id);
+
+        let roles = self.user_service.get_user_roles(user.id).await?;
+        let expires_in = self.config.impersonation_expiration_minutes * 60;
+        let token = self
+            .jwt_keys
+            .create_impersonation_jwt(&user, &roles, admin.sub, admin.sid, expires_in)?;
+        Ok(ImpersonationToken { token, expires_in })
+    }
+
+    pub async fn get_audit_events(
+        &self,
+        target_user_id: Option<i32>,
+        before: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<AuditEvent>, ApiError> {
+        Ok(self
+            .repository
+            .get_audit_events(target_user_id, before, limit.clamp(1, MAX_AUDIT_EVENTS))
+            .await?)
+    }
+}
+
+/// Argon2 takes tens of milliseconds of CPU, so it runs off the async workers.
+async fn run_blocking<T, F>(f: F, password: &str) -> Result<T, ApiError>
+where
+    F: FnOnce(&str) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || f(&password))
+        .await
+        .map_err(|_| ApiError::InternalServerError)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Mutex,
+    };
+
+    use async_trait::async_trait;
+
+    use crate::{modules::user::Role, utils::InMemoryMailer};
+
+    use super::*;
+
+    const EMAIL: &str = "owner@example.com";
+
+    #[derive(Default)]
+    struct FakeAuthRepository {
+        password_reset_tokens: Mutex<Vec<PasswordResetToken>>,
+        used_magic_links: Mutex<HashSet<String>>,
+        logged_out_users: Mutex<Vec<i32>>,
+    }
+
+    #[derive(Default)]
+    struct FakeUserRepository {
+        users: Mutex<Vec<User>>,
+        identities: Mutex<Vec<Identity>>,
+        password_hashes: Mutex<HashMap<i32, String>>,
+    }
+
+    #[async_trait]
+    #[allow(unused_variables)]
+    impl Repository for FakeAuthRepository {
+        async fn create_oauth_state(
+            &self,
+            oauth_state: &OAuthState,
+        ) -> Result<OAuthState, AuthError> {
+            unimplemented!()
+        }
+
+        async fn take_oauth_state(&self, state: &str) -> Result<Option<OAuthState>, AuthError> {
+            unimplemented!()
+        }
+
+        async fn delete_expired_oauth_states(&self) -> Result<(), AuthError> {
+            unimplemented!()
+        }
+
+        async fn create_refresh_token(
+            &self,
+            refresh_token: &RefreshToken,
+        ) -> Result<RefreshToken, AuthError> {
+            Ok(refresh_token.clone())
+        }
+
+        async fn get_refresh_token_by_hash(
+            &self,
+            token_hash: &str,
+        ) -> Result<Option<RefreshToken>, AuthError> {
+            unimplemented!()
+        }
+
+        async fn rotate_refresh_token(&self, id: Uuid) -> Result<bool, AuthError> {
+            unimplemented!()
+        }
+
+        async fn create_session(&self, session: &Session) -> Result<Session, AuthError> {
+            Ok(session.clone())
+        }
+
+        async fn get_session(&self, id: Uuid) -> Result<Option<Session>, AuthError> {
+            unimplemented!()
+        }
+
+        async fn get_active_sessions_by_user(
+            &self,
+            user_id: i32,
+        ) -> Result<Vec<Session>, AuthError> {
+            unimplemented!()
+        }
+
+        async fn touch_session(&self, id: Uuid) -> Result<Option<Session>, AuthError> {
+            unimplemented!()
+        }
+
+        async fn revoke_session(&self, id: Uuid) -> Result<(), AuthError> {
+            unimplemented!()
+        }
+
+        async fn revoke_token(
+            &self,
+            jti: &str,
+            user_id: i32,
+            expires_at: DateTime<Utc>,
+        ) -> Result<(), AuthError> {
+            unimplemented!()
+        }
+
+        async fn is_token_revoked(&self, jti: &str) -> Result<bool, AuthError> {
+            unimplemented!()
+        }
+
+        async fn delete_expired_revoked_tokens(&self) -> Result<(), AuthError> {
+            unimplemented!()
+        }
+
+        async fn revoke_all_user_tokens(
+            &self,
+            user_id: i32,
+            revoked_before: DateTime<Utc>,
+        ) -> Result<(), AuthError> {
+            self.logged_out_users.lock().unwrap().push(user_id);
+            Ok(())
+        }
+
+        async fn get_user_tokens_revoked_before(
+            &self,
+            user_id: i32,
+        ) -> Result<Option<DateTime<Utc>>, AuthError> {
+            unimplemented!()
+        }
+
+        async fn create_password_reset_token(
+            &self,
+            reset_token: &PasswordResetToken,
+        ) -> Result<(), AuthError> {
+            self.password_reset_tokens
+                .lock()
+                .unwrap()
+                .push(reset_token.clone());
+            Ok(())
+        }
+
+        async fn take_password_reset_token(
+            &self,
+            token_hash: &str,
+        ) -> Result<Option<PasswordResetToken>, AuthError> {
+            let mut reset_tokens = self.password_reset_tokens.lock().unwrap();
+            let index = reset_tokens
+                .iter()
+                .position(|reset_token| reset_token.token_hash == token_hash);
+            Ok(index.map(|index| reset_tokens.remove(index)))
+        }
+
+        async fn delete_expired_password_reset_tokens(&self) -> Result<(), AuthError> {
+            Ok(())
+        }
+
+        async fn use_magic_link(
+            &self,
+            jti: &str,
+            expires_at: DateTime<Utc>,
+        ) -> Result<bool, AuthError> {
+            Ok(self
+                .used_magic_links
+                .lock()
+                .unwrap()
+                .insert(jti.to_string()))
+        }
+
+        async fn delete_expired_magic_links(&self) -> Result<(), AuthError> {
+            Ok(())
+        }
+
+        async fn get_mfa(&self, user_id: i32) -> Result<Option<MfaSettings>, AuthError> {
+            Ok(None)
+        }
+
+        async fn save_mfa_secret(&self, user_id: i32, totp_secret: &str) -> Result<(), AuthError> {
+            unimplemented!()
+        }
+
+        async fn enable_mfa(
+            &self,
+            user_id: i32,
+            last_used_step: i64,
+            recovery_code_hashes: &[String],
+        ) -> Result<(), AuthError> {
+            unimplemented!()
+        }
+
+        async fn use_totp_step(&self, user_id: i32, step: i64) -> Result<bool, AuthError> {
+            unimplemented!()
+        }
+
+        async fn use_recovery_code(
+            &self,
+            user_id: i32,
+            code_hash: &str,
+        ) -> Result<bool, AuthError> {
+            unimplemented!()
+        }
+
+        async fn disable_mfa(&self, user_id: i32) -> Result<(), AuthError> {
+            unimplemented!()
+        }
+
+        async fn reencrypt_mfa_secrets(&self) -> Result<u64, AuthError> {
+            unimplemented!()
+        }
+
+        async fn record_mfa_attempt(
+            &self,
+            user_id: i32,
+            window_start: DateTime<Utc>,
+        ) -> Result<Option<i32>, AuthError> {
+            unimplemented!()
+        }
+
+        async fn clear_mfa_attempts(&self, user_id: i32) -> Result<(), AuthError> {
+            unimplemented!()
+        }
+
+        async fn use_mfa_pending_token(
+            &self,
+            jti: &str,
+            expires_at: DateTime<Utc>,
+        ) -> Result<bool, AuthError> {
+            unimplemented!()
+        }
+
+        async fn delete_expired_mfa_pending_tokens(&self) -> Result<(), AuthError> {
+            unimplemented!()
+        }
+
+        async fn create_api_key(&self, api_key: &ApiKey) -> Result<ApiKey, AuthError> {
+            unimplemented!()
+        }
+
+        async fn get_api_key_by_prefix(&self, prefix: &str) -> Result<Option<ApiKey>, AuthError> {
+            unimplemented!()
+        }
+
+        async fn get_api_keys_by_user(&self, user_id: i32) -> Result<Vec<ApiKey>, AuthError> {
+            unimplemented!()
+        }
+
+        async fn touch_api_key(&self, id: Uuid) -> Result<(), AuthError> {
+            unimplemented!()
+        }
+
+        async fn revoke_api_key(&self, id: Uuid, user_id: i32) -> Result<bool, AuthError> {
+            unimplemented!()
+        }
+
+        async fn create_audit_event(&self, event: &AuditEvent) -> Result<AuditEvent, AuthError> {
+            unimplemented!()
+        }
+
+        async fn get_audit_events(
+            &self,
+            target_user_id: Option<i32>,
+            before: Option<i64>,
+            limit: i64,
+        ) -> Result<Vec<AuditEvent>, AuthError> {
+            unimplemented!()
+        }
+    }
+
+    #[async_trait]
+    #[allow(unused_variables)]
+    impl user::ports::Repository for FakeUserRepository {
+        async fn get_user_by_email(&self, email: &str) -> Result<Option<User>, UserError> {
+            Ok(self
+                .users
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|user| user.email == email)
+                .cloned())
+        }
+
+        async fn get_user_by_customer_id(
+            &self,
+            customer_id: &str,
+        ) -> Result<Option<User>, UserError> {
+            unimplemented!()
+        }
+
+        async fn get_user_by_id(&self, id: i32) -> Result<Option<User>, UserError> {
+            Ok(self
+                .users
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|user| user.id == id)
+                .cloned())
+        }
+
+        async fn update_user(&self, user: &User) -> Result<User, UserError> {
+            let mut users = self.users.lock().unwrap();
+            let stored = users
+                .iter_mut()
+                .find(|stored| stored.id == user.id)
+                .ok_or(UserError::UserNotFound)?;
+            *stored = user.clone();
+            Ok(user.clone())
+        }
+
+        async fn create_user(&self, user: &User) -> Result<User, UserError> {
+            let mut users = self.users.lock().unwrap();
+            let user = User {
+                id: users.len() as i32 + 1,
+                ..user.clone()
+            };
+            users.push(user.clone());
+            Ok(user)
+        }
+
+        async fn create_user_with_identity(
+            &self,
+            user: &User,
+            identity: &Identity,
+        ) -> Result<User, UserError> {
+            let user = self.create_user(user).await?;
+            self.create_identity(&Identity {
+                user_id: user.id,
+                ..identity.clone()
+            })
+            .await?;
+            Ok(user)
+        }
+
+        async fn get_identity(
+            &self,
+            provider: &str,
+            provider_user_id: &str,
+        ) -> Result<Option<Identity>, UserError> {
+            Ok(self
+                .identities
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|identity| {
+                    identity.provider == provider && identity.provider_user_id == provider_user_id
+                })
+                .cloned())
+        }
+
+        async fn get_identities_by_user(&self, user_id: i32) -> Result<Vec<Identity>, UserError> {
+            Ok(self
+                .identities
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|identity| identity.user_id == user_id)
+                .cloned()
+                .collect())
+        }
+
+        async fn create_identity(&self, identity: &Identity) -> Result<Identity, UserError> {
+            let mut identities = self.identities.lock().unwrap();
+            let identity = Identity {
+                id: identities.len() as i32 + 1,
+                ..identity.clone()
+            };
+            identities.push(identity.clone());
+            Ok(identity)
+        }
+
+        async fn update_identity(&self, identity: &Identity) -> Result<Identity, UserError> {
+            unimplemented!()
+        }
+
+        async fn delete_identity(&self, id: i32) -> Result<(), UserError> {
+            self.identities
+                .lock()
+                .unwrap()
+                .retain(|identity| identity.id != id);
+            Ok(())
+        }
+
+        async fn get_roles(&self) -> Result<Vec<Role>, UserError> {
+            unimplemented!()
+        }
+
+        async fn get_user_roles(&self, user_id: i32) -> Result<Vec<Role>, UserError> {
+            Ok(Vec::new())
+        }
+
+        async fn add_user_role(&self, user_id: i32, role: &str) -> Result<(), UserError> {
+            unimplemented!()
+        }
+
+        async fn remove_user_role(&self, user_id: i32, role: &str) -> Result<bool, UserError> {
+            unimplemented!()
+        }
+
+        async fn get_password_hash(&self, user_id: i32) -> Result<Option<String>, UserError> {
+            Ok(self.password_hashes.lock().unwrap().get(&user_id).cloned())
+        }
+
+        async fn set_password_hash(
+            &self,
+            user_id: i32,
+            password_hash: &str,
+        ) -> Result<(), UserError> {
+            self.password_hashes
+                .lock()
+                .unwrap()
+                .insert(user_id, password_hash.to_string());
+            Ok(())
+        }
+
+        async fn delete_password_hash(&self, user_id: i32) -> Result<(), UserError> {
+            self.password_hashes.lock().unwrap().remove(&user_id);
+            Ok(())
+        }
+
+        async fn reencrypt_oauth_refresh_tokens(&self) -> Result<u64, UserError> {
+            unimplemented!()
+        }
+    }
+
+    struct Fixture {
+        service: Service,
+        auth_repository: Arc<FakeAuthRepository>,
+        user_service: Arc<user::Service>,
+        mailer: Arc<InMemoryMailer>,
+    }
+
+    fn fixture() -> Fixture {
+        let auth_repository = Arc::new(FakeAuthRepository::default());
+        let user_service = Arc::new(user::Service::new(Arc::new(FakeUserRepository::default())));
+        let mailer = Arc::new(InMemoryMailer::new());
+        Fixture {
+            service: Service::new(
+                auth_repository.clone(),
+                user_service.clone(),
+                mailer.clone(),
+            ),
+            auth_repository,
+            user_service,
+            mailer,
+        }
+    }
+
+    /// Signs up at a provider that does not check the email is the user's.
+    fn unverified_oidc_login() -> OAuthData {
+        OAuthData {
+            provider: OAuthProviderType::Oidc("unchecked".to_string()),
+            user_identifier: "oidc-user".to_string(),
+            name: "Someone".to_string(),
+            email: EMAIL.to_string(),
+            email_verified: false,
+            refresh_token: String::new(),
+            image_url: None,
+        }
+    }
+
+    /// The token of the link in the last email sent.
+    fn mailed_token(mailer: &InMemoryMailer) -> String {
+        let email = mailer.sent().pop().expect("no email sent");
+        email
+            .body
+            .split("?token=")
+            .nth(1)
+            .unwrap()
+            .lines()
+            .next()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_magic_link_unlinks_unverified_identities_of_the_account_it_claims() {
+        let fixture = fixture();
+        let squatter = fixture
+            .user_service
+            .sign_up_or_login(unverified_oidc_login())
+            .await
+            .unwrap();
+        assert!(!squatter.email_verified);
+
+        fixture.service.send_magic_link(EMAIL).await.unwrap();
+        let outcome = fixture
+            .service
+            .verify_magic_link(&mailed_token(&fixture.mailer), ClientInfo::default())
+            .await
+            .unwrap();
+
+        let LoginOutcome::Tokens(response) = outcome else {
+            panic!("expected tokens, got {:?}", outcome);
+        };
+        assert_eq!(response.user.id, squatter.id);
+        assert!(response.user.email_verified);
+        assert!(fixture
+            .user_service
+            .get_identities(squatter.id)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            *fixture.auth_repository.logged_out_users.lock().unwrap(),
+            vec![squatter.id]
+        );
+        assert!(matches!(
+            fixture
+                .user_service
+                .sign_up_or_login(unverified_oidc_login())
+                .await,
+            Err(ApiError::UserError(UserError::AccountExists))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_password_reset_unlinks_unverified_identities_before_logging_out() {
+        let fixture = fixture();
+        let squatter = fixture
+            .user_service
+            .sign_up_or_login(unverified_oidc_login())
+            .await
+            .unwrap();
+
+        fixture.service.request_password_reset(EMAIL).await.unwrap();
+        fixture
+            .service
+            .reset_password(&mailed_token(&fixture.mailer), "a new password")
+            .await
+            .unwrap();
+
+        let user = fixture
+            .user_service
+            .get_user_by_id(squatter.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(user.email_verified);
+        assert!(fixture
+            .user_service
+            .get_identities(squatter.id)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(fixture
+            .user_service
+            .get_password_hash(squatter.id)
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            *fixture.auth_repository.logged_out_users.lock().unwrap(),
+            vec![squatter.id]
+        );
+    }
+}
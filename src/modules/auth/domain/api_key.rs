@@ -0,0 +1,137 @@
+use chrono::{DateTime, Duration, Utc};
+use rand::RngCore;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use super::{generate_token, hash_token, AuthError};
+
+/// Every key starts with this, so it is told apart from a JWT and spotted by secret scanners.
+const API_KEY_PREFIX: &str = "sk_";
+const MAX_API_KEY_NAME_LEN: usize = 100;
+
+/// What a key can be limited to, a key without scopes can do everything its user can
+/// except for what needs a login (sessions, API keys, MFA, admin routes).
+pub const API_KEY_SCOPES: &[&str] = &["user:read", "checkout:write"];
+
+#[derive(Debug, Clone, FromRow)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub user_id: i32,
+    pub name: String,
+    /// Random public part of the key, used to look it up
+    pub prefix: String,
+    pub key_hash: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    /// Returns the key, which is only shown to the user once, and the row to store.
+    pub fn generate(
+        user_id: i32,
+        name: &str,
+        scopes: Vec<String>,
+        expires_in_days: Option<i64>,
+    ) -> Result<(String, Self), AuthError> {
+        let name = name.trim();
+        if name.is_empty() || name.len() > MAX_API_KEY_NAME_LEN {
+            return Err(AuthError::InvalidApiKeyRequest(format!(
+                "name must have 1 to {} characters",
+                MAX_API_KEY_NAME_LEN
+            )));
+        }
+        if let Some(scope) = scopes
+            .iter()
+            .find(|scope| !API_KEY_SCOPES.contains(&scope.as_str()))
+        {
+            return Err(AuthError::InvalidApiKeyRequest(format!(
+                "unknown scope '{}'",
+                scope
+            )));
+        }
+        if expires_in_days.is_some_and(|days| days <= 0) {
+            return Err(AuthError::InvalidApiKeyRequest(
+                "expires_in_days must be positive".to_string(),
+            ));
+        }
+
+        let mut prefix = [0u8; 6];
+        rand::thread_rng().fill_bytes(&mut prefix);
+        let prefix = hex::encode(prefix);
+        let key = format!("{}{}_{}", API_KEY_PREFIX, prefix, generate_token());
+
+        let created_at = Utc::now();
+        let api_key = ApiKey {
+            id: Uuid::new_v4(),
+            user_id,
+            name: name.to_string(),
+            prefix,
+            key_hash: hash_token(&key),
+            scopes,
+            created_at,
+            expires_at: expires_in_days.map(|days| created_at + Duration::days(days)),
+            last_used_at: None,
+            revoked_at: None,
+        };
+        Ok((key, api_key))
+    }
+
+    /// Checks the presented key against this row found by its prefix.
+    pub fn is_valid(&self, key: &str) -> bool {
+        self.key_hash == hash_token(key)
+            && self.revoked_at.is_none()
+            && self
+                .expires_at
+                .is_none_or(|expires_at| expires_at > Utc::now())
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.is_empty() || self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+/// The lookup prefix of something that looks like an API key.
+pub fn api_key_prefix(key: &str) -> Option<&str> {
+    let (prefix, _) = key.strip_prefix(API_KEY_PREFIX)?.split_once('_')?;
+    Some(prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_key_is_found_by_prefix_and_validated() {
+        let (key, api_key) =
+            ApiKey::generate(1, "deploy script", vec!["user:read".to_string()], Some(30)).unwrap();
+
+        assert!(key.starts_with("sk_"));
+        assert_eq!(api_key_prefix(&key), Some(api_key.prefix.as_str()));
+        assert!(api_key.is_valid(&key));
+        assert!(!api_key.is_valid(&format!("{}x", key)));
+        assert!(api_key.has_scope("user:read"));
+        assert!(!api_key.has_scope("checkout:write"));
+
+        let revoked = ApiKey {
+            revoked_at: Some(Utc::now()),
+            ..api_key.clone()
+        };
+        assert!(!revoked.is_valid(&key));
+        let expired = ApiKey {
+            expires_at: Some(Utc::now() - Duration::minutes(1)),
+            ..api_key
+        };
+        assert!(!expired.is_valid(&key));
+        assert_eq!(api_key_prefix("eyJhbGciOiJSUzI1NiJ9.e30.sig"), None);
+    }
+
+    #[test]
+    fn test_generate_rejects_unknown_scopes() {
+        assert!(ApiKey::generate(1, "ci", vec!["admin".to_string()], None).is_err());
+        assert!(ApiKey::generate(1, " ", Vec::new(), None).is_err());
+        assert!(ApiKey::generate(1, "ci", Vec::new(), Some(0)).is_err());
+    }
+}
@@ -1,8 +1,11 @@
 use jsonwebtoken::errors::Error as JwtError;
 use reqwest::Error as ReqwestError;
 use serde_json::Error as SerdeError;
+use sqlx::Error as SqlxError;
 use thiserror::Error;
 
+use crate::utils::CipherError;
+
 #[derive(Error, Debug)]
 pub enum AuthError {
     #[error("Authorization failed")]
@@ -17,9 +20,75 @@ pub enum AuthError {
     #[error("JWT creation failed: {0}")]
     JwtCreationFailed(String),
 
+    #[error("The provider account has no verified email")]
+    EmailNotVerified,
+
+    #[error("Unknown OAuth provider: {0}")]
+    UnknownProvider(String),
+
     #[error("Invalid callback data provided")]
     InvalidCallbackData,
 
+    #[error("Token has been revoked")]
+    TokenRevoked,
+
+    #[error("Session not found")]
+    SessionNotFound,
+
+    #[error("Invalid or expired refresh token")]
+    InvalidRefreshToken,
+
+    #[error("Invalid or expired OAuth state")]
+    InvalidOAuthState,
+
+    #[error("Invalid email or password")]
+    InvalidCredentials,
+
+    #[error("Invalid email address")]
+    InvalidEmail,
+
+    #[error("Invalid password: {0}")]
+    InvalidPassword(String),
+
+    #[error("Invalid, expired or already used magic link")]
+    InvalidMagicLink,
+
+    #[error("Invalid or expired password reset token")]
+    InvalidPasswordResetToken,
+
+    #[error("Invalid or expired email verification link")]
+    InvalidEmailVerification,
+
+    #[error("Verify your email before logging in")]
+    AccountNotVerified,
+
+    #[error("Invalid, expired or revoked API key")]
+    InvalidApiKey,
+
+    #[error("Invalid API key: {0}")]
+    InvalidApiKeyRequest(String),
+
+    #[error("API key not found")]
+    ApiKeyNotFound,
+
+    #[error("Cannot impersonate yourself")]
+    SelfImpersonation,
+
+    #[error("Two-factor authentication is already enabled")]
+    MfaAlreadyEnabled,
+
+    #[error("Two-factor authentication is not set up")]
+    MfaNotEnrolled,
+
+    #[error("Invalid two-factor authentication code")]
+    InvalidMfaCode,
+
+    #[error("Too many invalid two-factor authentication codes")]
+    MfaTooManyAttempts,
+
+    #[error("Password hashing failed: {0}")]
+    PasswordHashError(String),
+
     #[error("OAuth2 request token error: {0}")]
     OAuth2RequestTokenError(String),
 
@@ -31,4 +100,10 @@ pub enum AuthError {
 
     #[error("JSON parsing error: {0}")]
     SerdeParseError(#[from] SerdeError), // From serde_json::Error
+
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] SqlxError),
+
+    #[error("Encryption error: {0}")]
+    EncryptionError(#[from] CipherError),
 }
@@ -1,45 +1,457 @@
+use std::fs;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use chrono::Utc;
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
-use serde::{Deserialize, Serialize};
+use ed25519_dalek::{
+    pkcs8::{DecodePrivateKey, DecodePublicKey},
+    SigningKey, VerifyingKey,
+};
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey},
+    traits::PublicKeyParts,
+    RsaPrivateKey, RsaPublicKey,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use uuid::Uuid;
 
-use crate::{modules::user::User, utils::Config};
+use crate::{
+    modules::user::{Role, User},
+    utils::Config,
+};
 
 use super::AuthError;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
+    pub sub: i32,
+    /// The login this token belongs to, shared with its refresh token family
+    pub sid: Uuid,
+    pub exp: i64,
+    pub iat: i64,
+    pub nbf: i64,
+    pub iss: String,
+    pub aud: String,
+    pub jti: String,
+    /// Roles of the user when the token was issued, changes apply on the next refresh
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Union of the permissions of `roles`
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    /// The admin acting as `sub` when the token is an impersonation (RFC 8693)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Actor {
+    pub sub: i32,
+}
+
+impl Claims {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+
+    pub fn is_impersonation(&self) -> bool {
+        self.act.is_some()
+    }
+}
+
+/// Claims of the token mailed in a magic link, only accepted by the magic link
+/// endpoint because of its own audience.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MagicLinkClaims {
+    pub email: String,
+    pub exp: i64,
+    pub iat: i64,
+    pub nbf: i64,
+    pub iss: String,
+    pub aud: String,
+    pub jti: String,
+}
+
+/// Claims of the token mailed to confirm the email of a password sign up, only
+/// valid while the user still has that email.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailVerificationClaims {
+    pub sub: i32,
+    pub email: String,
+    pub exp: i64,
+    pub iat: i64,
+    pub nbf: i64,
+    pub iss: String,
+    pub aud: String,
+    pub jti: String,
+}
+
+/// Claims of the token a login returns instead of an access token while the
+/// second factor is pending, only accepted by `POST /auth/mfa/verify`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MfaPendingClaims {
     pub sub: i32,
     pub exp: i64,
+    pub iat: i64,
+    pub nbf: i64,
+    pub iss: String,
+    pub aud: String,
+    pub jti: String,
 }
 
-pub fn create_jwt(user: &User) -> Result<String, AuthError> {
-    let config = Config::from_env();
-    let expiration_seconds = 3600; // Define the expiration to be in 1 hour
-    let claims = Claims {
-        sub: user.id,
-        exp: (Utc::now() + chrono::Duration::seconds(expiration_seconds)).timestamp(), // Create an unix timestamp
+const MAGIC_LINK_AUDIENCE_SUFFIX: &str = "magic-link";
+const MFA_PENDING_AUDIENCE_SUFFIX: &str = "mfa-pending";
+const EMAIL_VERIFICATION_AUDIENCE_SUFFIX: &str = "email-verification";
+
+struct VerificationKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    decoding_key: DecodingKey,
+    /// Public form published in the JWKS, `None` for the shared HMAC secret
+    jwk: Option<Jwk>,
+}
+
+/// Signs our access tokens with the active key and verifies them against every
+/// key that is still accepted, so keys can be rotated without logging users out.
+pub struct JwtKeys {
+    signing_key: EncodingKey,
+    signing_header: Header,
+    verification_keys: Vec<VerificationKey>,
+    issuer: String,
+    audience: String,
+    expiration_seconds: i64,
+    leeway_seconds: u64,
+}
+
+impl JwtKeys {
+    /// Uses the RS256/EdDSA key in `JWT_SIGNING_KEY_PATH` when set and falls back
+    /// to HS256 with `JWT_SECRET` otherwise.
+    pub fn from_config(config: &Config) -> Self {
+        let (signing_key, signing_header, mut verification_keys) =
+            match &config.jwt_signing_key_path {
+                Some(path) => {
+                    let kid = config
+                        .jwt_signing_key_id
+                        .clone()
+                        .expect("JWT_SIGNING_KEY_ID not set");
+                    let pem = fs::read(path).expect("Could not read JWT_SIGNING_KEY_PATH");
+                    let (signing_key, verification_key) = load_private_key(kid, &pem)
+                        .expect("JWT_SIGNING_KEY_PATH is not a valid RSA or Ed25519 private key");
+                    let mut header = Header::new(verification_key.algorithm);
+                    header.kid = verification_key.kid.clone();
+                    (signing_key, header, vec![verification_key])
+                }
+                None => {
+                    let secret = config.jwt_secret.as_ref().expect("JWT_SECRET not set");
+                    let verification_key = VerificationKey {
+                        kid: None,
+                        algorithm: Algorithm::HS256,
+                        decoding_key: DecodingKey::from_secret(secret.as_ref()),
+                        jwk: None,
+                    };
+                    (
+                        EncodingKey::from_secret(secret.as_ref()),
+                        Header::default(),
+                        vec![verification_key],
+                    )
+                }
+            };
+
+        for (kid, path) in &config.jwt_verification_keys {
+            let pem = fs::read(path)
+                .unwrap_or_else(|_| panic!("Could not read verification key '{}'", kid));
+            let verification_key = load_public_key(kid.clone(), &pem).unwrap_or_else(|_| {
+                panic!(
+                    "Verification key '{}' is not a valid RSA or Ed25519 public key",
+                    kid
+                )
+            });
+            verification_keys.push(verification_key);
+        }
+
+        Self {
+            signing_key,
+            signing_header,
+            verification_keys,
+            issuer: config.jwt_issuer.clone(),
+            audience: config.jwt_audience.clone(),
+            expiration_seconds: config.jwt_expiration_seconds,
+            leeway_seconds: config.jwt_leeway_seconds,
+        }
+    }
+
+    pub fn create_jwt(
+        &self,
+        user: &User,
+        roles: &[Role],
+        session_id: Uuid,
+    ) -> Result<String, AuthError> {
+        self.create_access_token(user, roles, session_id, None, self.expiration_seconds)
+    }
+
+    /// An access token of `user` for the admin `actor_id`, bound to the admin's
+    /// session so logging the admin out ends it too.
+    pub fn create_impersonation_jwt(
+        &self,
+        user: &User,
+        roles: &[Role],
+        actor_id: i32,
+        actor_session_id: Uuid,
+        expiration_seconds: i64,
+    ) -> Result<String, AuthError> {
+        self.create_access_token(
+            user,
+            roles,
+            actor_session_id,
+            Some(Actor { sub: actor_id }),
+            expiration_seconds,
+        )
+    }
+
+    fn create_access_token(
+        &self,
+        user: &User,
+        roles: &[Role],
+        session_id: Uuid,
+        act: Option<Actor>,
+        expiration_seconds: i64,
+    ) -> Result<String, AuthError> {
+        let now = Utc::now();
+        let mut permissions: Vec<String> = roles
+            .iter()
+            .flat_map(|role| role.permissions.iter().cloned())
+            .collect();
+        permissions.sort();
+        permissions.dedup();
+        let claims = Claims {
+            sub: user.id,
+            sid: session_id,
+            exp: (now + chrono::Duration::seconds(expiration_seconds)).timestamp(), // Create an unix timestamp
+            iat: now.timestamp(),
+            nbf: now.timestamp(),
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            jti: Uuid::new_v4().to_string(),
+            roles: roles.iter().map(|role| role.name.clone()).collect(),
+            permissions,
+            act,
+        };
+        self.sign(&claims)
+    }
+
+    pub fn verify_jwt(&self, token: &str) -> Result<Claims, AuthError> {
+        self.verify(token, &self.audience)
+    }
+
+    pub fn create_magic_link_token(
+        &self,
+        email: &str,
+        expiration_minutes: i64,
+    ) -> Result<String, AuthError> {
+        let now = Utc::now();
+        let claims = MagicLinkClaims {
+            email: email.to_string(),
+            exp: (now + chrono::Duration::minutes(expiration_minutes)).timestamp(),
+            iat: now.timestamp(),
+            nbf: now.timestamp(),
+            iss: self.issuer.clone(),
+            aud: self.purpose_audience(MAGIC_LINK_AUDIENCE_SUFFIX),
+            jti: Uuid::new_v4().to_string(),
+        };
+        self.sign(&claims)
+    }
+
+    pub fn verify_magic_link_token(&self, token: &str) -> Result<MagicLinkClaims, AuthError> {
+        self.verify(token, &self.purpose_audience(MAGIC_LINK_AUDIENCE_SUFFIX))
+    }
+
+    pub fn create_email_verification_token(
+        &self,
+        user_id: i32,
+        email: &str,
+        expiration_minutes: i64,
+    ) -> Result<String, AuthError> {
+        let now = Utc::now();
+        let claims = EmailVerificationClaims {
+            sub: user_id,
+            email: email.to_string(),
+            exp: (now + chrono::Duration::minutes(expiration_minutes)).timestamp(),
+            iat: now.timestamp(),
+            nbf: now.timestamp(),
+            iss: self.issuer.clone(),
+            aud: self.purpose_audience(EMAIL_VERIFICATION_AUDIENCE_SUFFIX),
+            jti: Uuid::new_v4().to_string(),
+        };
+        self.sign(&claims)
+    }
+
+    pub fn verify_email_verification_token(
+        &self,
+        token: &str,
+    ) -> Result<EmailVerificationClaims, AuthError> {
+        self.verify(
+            token,
+            &self.purpose_audience(EMAIL_VERIFICATION_AUDIENCE_SUFFIX),
+        )
+    }
+
+    pub fn create_mfa_pending_token(
+        &self,
+        user_id: i32,
+        expiration_minutes: i64,
+    ) -> Result<String, AuthError> {
+        let now = Utc::now();
+        let claims = MfaPendingClaims {
+            sub: user_id,
+            exp: (now + chrono::Duration::minutes(expiration_minutes)).timestamp(),
+            iat: now.timestamp(),
+            nbf: now.timestamp(),
+            iss: self.issuer.clone(),
+            aud: self.purpose_audience(MFA_PENDING_AUDIENCE_SUFFIX),
+            jti: Uuid::new_v4().to_string(),
+        };
+        self.sign(&claims)
+    }
+
+    pub fn verify_mfa_pending_token(&self, token: &str) -> Result<MfaPendingClaims, AuthError> {
+        self.verify(token, &self.purpose_audience(MFA_PENDING_AUDIENCE_SUFFIX))
+    }
+
+    /// Tokens for other purposes than API access get their own audience, so none
+    /// of them can be used in place of another.
+    fn purpose_audience(&self, purpose: &str) -> String {
+        format!("{}:{}", self.audience, purpose)
+    }
+
+    fn sign<T: Serialize>(&self, claims: &T) -> Result<String, AuthError> {
+        encode(&self.signing_header, claims, &self.signing_key)
+            .map_err(|err| AuthError::JwtCreationFailed(err.to_string()))
+    }
+
+    fn verify<T: DeserializeOwned>(&self, token: &str, audience: &str) -> Result<T, AuthError> {
+        let header = decode_header(token)?;
+        // Only the algorithm of the matching key is accepted, never the one the token claims
+        let key = self
+            .verification_keys
+            .iter()
+            .find(|key| key.kid == header.kid)
+            .ok_or_else(|| AuthError::InvalidTokenError("Unknown signing key".to_string()))?;
+
+        let mut validation = Validation::new(key.algorithm);
+        validation.leeway = self.leeway_seconds;
+        validation.validate_nbf = true;
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[audience]);
+
+        decode::<T>(token, &key.decoding_key, &validation)
+            .map(|data| data.claims)
+            .map_err(AuthError::JwtError)
+    }
+
+    /// Public keys other services need to verify our tokens on their own.
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self
+                .verification_keys
+                .iter()
+                .filter_map(|key| key.jwk.clone())
+                .collect(),
+        }
+    }
+}
+
+fn load_private_key(kid: String, pem: &[u8]) -> Result<(EncodingKey, VerificationKey), AuthError> {
+    let pem_str =
+        std::str::from_utf8(pem).map_err(|err| AuthError::InvalidTokenError(err.to_string()))?;
+
+    let (encoding_key, public_key) = if let Ok(key) =
+        RsaPrivateKey::from_pkcs8_pem(pem_str).or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem_str))
+    {
+        (
+            EncodingKey::from_rsa_pem(pem)?,
+            PublicKey::Rsa(key.to_public_key()),
+        )
+    } else {
+        let key = SigningKey::from_pkcs8_pem(pem_str)
+            .map_err(|err| AuthError::InvalidTokenError(err.to_string()))?;
+        (
+            EncodingKey::from_ed_pem(pem)?,
+            PublicKey::Ed25519(key.verifying_key()),
+        )
     };
 
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(config.jwt_secret.as_ref()),
-    )
-    .map_err(|err| AuthError::JwtCreationFailed(err.to_string()))
+    Ok((encoding_key, public_key.into_verification_key(kid)?))
 }
 
-pub fn verify_jwt(token: &str) -> Result<Claims, AuthError> {
-    let config = Config::from_env();
-    let mut validation = Validation::new(Algorithm::HS256);
-    validation.validate_exp = false;
-
-    decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(config.jwt_secret.as_ref()),
-        &validation,
-    )
-    .map(|data| data.claims)
-    .map_err(|err| AuthError::JwtError(err))
+fn load_public_key(kid: String, pem: &[u8]) -> Result<VerificationKey, AuthError> {
+    let pem_str =
+        std::str::from_utf8(pem).map_err(|err| AuthError::InvalidTokenError(err.to_string()))?;
+
+    let public_key = if let Ok(key) = RsaPublicKey::from_public_key_pem(pem_str)
+        .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem_str))
+    {
+        PublicKey::Rsa(key)
+    } else {
+        PublicKey::Ed25519(
+            VerifyingKey::from_public_key_pem(pem_str)
+                .map_err(|err| AuthError::InvalidTokenError(err.to_string()))?,
+        )
+    };
+    public_key.into_verification_key(kid)
+}
+
+enum PublicKey {
+    Rsa(RsaPublicKey),
+    Ed25519(VerifyingKey),
+}
+
+impl PublicKey {
+    fn into_verification_key(self, kid: String) -> Result<VerificationKey, AuthError> {
+        let (algorithm, key_algorithm, parameters) = match self {
+            PublicKey::Rsa(key) => (
+                Algorithm::RS256,
+                KeyAlgorithm::RS256,
+                AlgorithmParameters::RSA(RSAKeyParameters {
+                    key_type: RSAKeyType::RSA,
+                    n: URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+                    e: URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+                }),
+            ),
+            PublicKey::Ed25519(key) => (
+                Algorithm::EdDSA,
+                KeyAlgorithm::EdDSA,
+                AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x: URL_SAFE_NO_PAD.encode(key.to_bytes()),
+                }),
+            ),
+        };
+
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(key_algorithm),
+                key_id: Some(kid.clone()),
+                ..Default::default()
+            },
+            algorithm: parameters,
+        };
+
+        Ok(VerificationKey {
+            kid: Some(kid),
+            algorithm,
+            decoding_key: DecodingKey::from_jwk(&jwk)?,
+            jwk: Some(jwk),
+        })
+    }
 }
 
 #[cfg(test)]
@@ -55,25 +467,174 @@ mod tests {
             id: 1,
             stripe_customer_id: None,
             email: "test".into(),
-            oauth_provider: "google".into(),
-            oauth_id: "1234".into(),
-            oauth_refresh_token: "1234".into(),
+            email_verified: true,
             image_url: None,
             created_at: Utc::now(),
         }
     }
 
+    fn test_key_path(name: &str) -> String {
+        format!(
+            "{}/src/modules/auth/domain/test_keys/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        )
+    }
+
+    fn ed25519_config(kid: &str, key: &str, verification_keys: Vec<(String, String)>) -> Config {
+        let mut config = Config::from_env();
+        config.jwt_signing_key_id = Some(kid.to_string());
+        config.jwt_signing_key_path = Some(test_key_path(key));
+        config.jwt_verification_keys = verification_keys;
+        config
+    }
+
     #[test]
     fn test_create_and_verify_jwt() {
+        let keys = JwtKeys::from_config(&Config::from_env());
         let user = get_test_user();
-        let token = create_jwt(&user).expect("Failed to create JWT");
+        let token = keys
+            .create_jwt(&user, &[], Uuid::new_v4())
+            .expect("Failed to create JWT");
 
         // Verify the token
-        let claims = verify_jwt(&token).expect("Failed to verify JWT");
+        let claims = keys.verify_jwt(&token).expect("Failed to verify JWT");
 
         // Check if the 'sub' field in the token matches the user ID
         assert_eq!(claims.sub, TEST_USER_ID, "JWT 'sub' field mismatch");
 
         // Ensure that the 'exp' field is correctly set and not None
+        assert!(
+            claims.exp > Utc::now().timestamp(),
+            "JWT 'exp' is not in the future"
+        );
+    }
+
+    #[test]
+    fn test_jwt_carries_roles_and_permissions() {
+        let keys = JwtKeys::from_config(&Config::from_env());
+        let roles = [
+            Role {
+                name: "admin".to_string(),
+                permissions: vec!["users:manage".to_string(), "billing:read".to_string()],
+            },
+            Role {
+                name: "support".to_string(),
+                permissions: vec!["billing:read".to_string()],
+            },
+        ];
+        let token = keys
+            .create_jwt(&get_test_user(), &roles, Uuid::new_v4())
+            .unwrap();
+
+        let claims = keys.verify_jwt(&token).unwrap();
+        assert!(claims.has_role("admin") && claims.has_role("support"));
+        assert!(!claims.has_role("owner"));
+        assert_eq!(claims.permissions, vec!["billing:read", "users:manage"]);
+        assert!(!claims.is_impersonation());
+    }
+
+    #[test]
+    fn test_impersonation_jwt_names_the_actor() {
+        let keys = JwtKeys::from_config(&Config::from_env());
+        let token = keys
+            .create_impersonation_jwt(&get_test_user(), &[], 7, Uuid::new_v4(), 60)
+            .unwrap();
+
+        let claims = keys.verify_jwt(&token).unwrap();
+        assert_eq!(claims.sub, TEST_USER_ID);
+        assert_eq!(claims.act.map(|act| act.sub), Some(7));
+        assert!(claims.exp <= Utc::now().timestamp() + 60);
+    }
+
+    #[test]
+    fn test_verify_jwt_rejects_expired_token() {
+        let config = Config::from_env();
+        let keys = JwtKeys::from_config(&config);
+        let issued_at = Utc::now() - chrono::Duration::hours(2);
+        let claims = Claims {
+            sub: TEST_USER_ID,
+            sid: Uuid::new_v4(),
+            exp: (issued_at + chrono::Duration::hours(1)).timestamp(),
+            iat: issued_at.timestamp(),
+            nbf: issued_at.timestamp(),
+            iss: config.jwt_issuer,
+            aud: config.jwt_audience,
+            jti: Uuid::new_v4().to_string(),
+            roles: Vec::new(),
+            permissions: Vec::new(),
+            act: None,
+        };
+        let token = encode(&Header::default(), &claims, &keys.signing_key).unwrap();
+
+        assert!(keys.verify_jwt(&token).is_err(), "Expired JWT was accepted");
+    }
+
+    #[test]
+    fn test_purpose_tokens_are_not_access_tokens() {
+        let keys = JwtKeys::from_config(&Config::from_env());
+        let magic_link = keys
+            .create_magic_link_token("test@example.com", 15)
+            .expect("Failed to create magic link token");
+        let access_token = keys
+            .create_jwt(&get_test_user(), &[], Uuid::new_v4())
+            .unwrap();
+
+        let claims = keys.verify_magic_link_token(&magic_link).unwrap();
+        assert_eq!(claims.email, "test@example.com");
+        assert!(keys.verify_jwt(&magic_link).is_err());
+        assert!(keys.verify_magic_link_token(&access_token).is_err());
+
+        let mfa_pending = keys.create_mfa_pending_token(TEST_USER_ID, 5).unwrap();
+        assert_eq!(
+            keys.verify_mfa_pending_token(&mfa_pending).unwrap().sub,
+            TEST_USER_ID
+        );
+        assert!(keys.verify_jwt(&mfa_pending).is_err());
+        assert!(keys.verify_magic_link_token(&mfa_pending).is_err());
+        assert!(keys.verify_mfa_pending_token(&access_token).is_err());
+
+        let email_verification = keys
+            .create_email_verification_token(TEST_USER_ID, "test@example.com", 60)
+            .unwrap();
+        let claims = keys
+            .verify_email_verification_token(&email_verification)
+            .unwrap();
+        assert_eq!(
+            (claims.sub, claims.email.as_str()),
+            (TEST_USER_ID, "test@example.com")
+        );
+        assert!(keys.verify_jwt(&email_verification).is_err());
+        assert!(keys.verify_magic_link_token(&email_verification).is_err());
+        assert!(keys.verify_email_verification_token(&magic_link).is_err());
+    }
+
+    #[test]
+    fn test_rotated_eddsa_keys() {
+        let previous_keys =
+            JwtKeys::from_config(&ed25519_config("previous", "ed25519_previous.pem", vec![]));
+        let current_keys = JwtKeys::from_config(&ed25519_config(
+            "current",
+            "ed25519_current.pem",
+            vec![(
+                "previous".to_string(),
+                test_key_path("ed25519_previous.pub.pem"),
+            )],
+        ));
+
+        let old_token = previous_keys
+            .create_jwt(&get_test_user(), &[], Uuid::new_v4())
+            .unwrap();
+        let new_token = current_keys
+            .create_jwt(&get_test_user(), &[], Uuid::new_v4())
+            .unwrap();
+
+        assert_eq!(decode_header(&new_token).unwrap().alg, Algorithm::EdDSA);
+        assert!(current_keys.verify_jwt(&old_token).is_ok());
+        assert!(current_keys.verify_jwt(&new_token).is_ok());
+        assert!(previous_keys.verify_jwt(&new_token).is_err());
+
+        let jwks = current_keys.jwks();
+        assert!(jwks.find("current").is_some() && jwks.find("previous").is_some());
     }
 }
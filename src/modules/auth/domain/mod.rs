@@ -1,3 +1,6 @@
+mod api_key;
+pub use api_key::*;
+
 mod error;
 pub use error::*;
 
@@ -6,3 +9,17 @@ pub use jwt::*;
 
 mod model;
 pub use model::*;
+
+mod password;
+pub use password::*;
+
+mod refresh_token;
+pub use refresh_token::*;
+
+mod token;
+pub use token::*;
+
+mod totp;
+pub use totp::*;
+
+pub mod ports;
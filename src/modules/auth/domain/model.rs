@@ -1,15 +1,40 @@
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use uuid::Uuid;
 
 use crate::modules::user::User;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// How long a user has to complete the provider login before the state expires.
+const OAUTH_STATE_TTL_MINUTES: i64 = 10;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub enum OAuthProviderType {
     Google,
+    Github,
+    /// Generic OpenID Connect issuer, named after its `OIDC_PROVIDERS` entry
+    Oidc(String),
+}
+impl OAuthProviderType {
+    pub fn as_str(&self) -> &str {
+        match self {
+            OAuthProviderType::Google => "google",
+            OAuthProviderType::Github => "github",
+            OAuthProviderType::Oidc(name) => name,
+        }
+    }
 }
 impl Into<String> for OAuthProviderType {
     fn into(self) -> String {
-        match self {
-            OAuthProviderType::Google => "google".into(),
+        self.as_str().to_string()
+    }
+}
+impl From<String> for OAuthProviderType {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "google" => OAuthProviderType::Google,
+            "github" => OAuthProviderType::Github,
+            _ => OAuthProviderType::Oidc(name),
         }
     }
 }
@@ -20,12 +45,199 @@ pub struct OAuthData {
     pub user_identifier: String,
     pub name: String,
     pub email: String,
+    /// Whether the provider vouches that the user owns `email`
+    pub email_verified: bool,
     pub refresh_token: String,
     pub image_url: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
 pub struct OAuthResponse {
     pub user: User,
     pub token: String,
+    pub refresh_token: String,
+}
+
+/// Result of a login, which stops before issuing tokens when the user has a
+/// second factor to check.
+#[derive(Debug)]
+pub enum LoginOutcome {
+    Tokens(OAuthResponse),
+    MfaRequired { mfa_token: String, expires_in: i64 },
+}
+
+#[derive(Debug)]
+pub struct MfaEnrollment {
+    pub secret: String,
+    pub provisioning_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaCodeRequest {
+    /// A TOTP code, or a recovery code where the endpoint accepts one
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaVerifyRequest {
+    pub mfa_token: String,
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterRequest {
+    pub email: String,
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordLoginRequest {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MagicLinkRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MagicLinkQuery {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmailVerificationQuery {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct OAuthState {
+    pub state: String,
+    pub provider: String,
+    pub pkce_verifier: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Set when the flow links the provider to this user instead of logging in
+    pub link_user_id: Option<i32>,
+}
+
+impl OAuthState {
+    pub fn new(
+        provider: &OAuthProviderType,
+        state: &str,
+        pkce_verifier: &str,
+        link_user_id: Option<i32>,
+    ) -> Self {
+        let created_at = Utc::now();
+        OAuthState {
+            state: state.to_string(),
+            provider: provider.as_str().to_string(),
+            pkce_verifier: pkce_verifier.to_string(),
+            link_user_id,
+            created_at,
+            expires_at: created_at + Duration::minutes(OAUTH_STATE_TTL_MINUTES),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at < Utc::now()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProviderInfo {
+    pub name: String,
+    pub redirect_url: String,
+}
+
+/// Where a login came from, recorded on its session.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: i32,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl Session {
+    pub fn new(user_id: i32, client_info: ClientInfo) -> Self {
+        let created_at = Utc::now();
+        Session {
+            id: Uuid::new_v4(),
+            user_id,
+            user_agent: client_info.user_agent,
+            ip_address: client_info.ip_address,
+            created_at,
+            last_seen_at: created_at,
+            revoked_at: None,
+        }
+    }
+}
+
+pub const AUDIT_IMPERSONATION_STARTED: &str = "impersonation.started";
+
+#[derive(Debug, Clone, FromRow)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub actor_user_id: i32,
+    pub action: String,
+    pub target_user_id: Option<i32>,
+    pub reason: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImpersonateRequest {
+    /// Why support needs to act as the user, kept in the audit log
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditLogQuery {
+    pub target_user_id: Option<i32>,
+    /// Only events older than this id, for paging
+    pub before: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug)]
+pub struct ImpersonationToken {
+    pub token: String,
+    pub expires_in: i64,
 }
@@ -0,0 +1,112 @@
+use std::sync::OnceLock;
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::FromRow;
+
+use super::{generate_token, hash_token, AuthError};
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+/// Argon2 is slow on purpose, very long inputs only burn CPU.
+const MAX_PASSWORD_LENGTH: usize = 128;
+
+pub fn validate_password(password: &str) -> Result<(), AuthError> {
+    let length = password.chars().count();
+    if length < MIN_PASSWORD_LENGTH {
+        return Err(AuthError::InvalidPassword(format!(
+            "must be at least {} characters long",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+    if length > MAX_PASSWORD_LENGTH {
+        return Err(AuthError::InvalidPassword(format!(
+            "must be at most {} characters long",
+            MAX_PASSWORD_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+/// Hashes with Argon2id and a random salt into a PHC string, which carries the
+/// parameters so they can be raised later without breaking existing hashes.
+pub fn hash_password(password: &str) -> Result<String, AuthError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| AuthError::PasswordHashError(err.to_string()))
+}
+
+/// Checks the password against `password_hash`, or against a throwaway hash when
+/// the user has no password so unknown emails take as long as wrong passwords.
+pub fn verify_password(password_hash: Option<&str>, password: &str) -> bool {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    let dummy_hash = DUMMY_HASH.get_or_init(|| {
+        hash_password(&generate_token()).expect("Could not hash the dummy password")
+    });
+
+    let Ok(hash) = PasswordHash::new(password_hash.unwrap_or(dummy_hash)) else {
+        return false;
+    };
+    let verified = Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok();
+    verified && password_hash.is_some()
+}
+
+/// One-time token mailed to reset a password, stored hashed like refresh tokens.
+#[derive(Debug, Clone, FromRow)]
+pub struct PasswordResetToken {
+    pub token_hash: String,
+    pub user_id: i32,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl PasswordResetToken {
+    pub fn generate(user_id: i32, expiration_minutes: i64) -> (String, Self) {
+        let token = generate_token();
+        let created_at = Utc::now();
+        let reset_token = PasswordResetToken {
+            token_hash: hash_token(&token),
+            user_id,
+            created_at,
+            expires_at: created_at + Duration::minutes(expiration_minutes),
+        };
+        (token, reset_token)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at < Utc::now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_and_verify_password() {
+        let hash = hash_password("correct horse battery").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert_ne!(hash, hash_password("correct horse battery").unwrap());
+
+        assert!(verify_password(Some(&hash), "correct horse battery"));
+        assert!(!verify_password(Some(&hash), "wrong horse battery"));
+        assert!(!verify_password(None, "correct horse battery"));
+        assert!(!verify_password(
+            Some("not a phc string"),
+            "correct horse battery"
+        ));
+    }
+
+    #[test]
+    fn test_validate_password_length() {
+        assert!(validate_password("short").is_err());
+        assert!(validate_password("long enough").is_ok());
+        assert!(validate_password(&"a".repeat(MAX_PASSWORD_LENGTH + 1)).is_err());
+    }
+}
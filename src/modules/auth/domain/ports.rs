@@ -0,0 +1,123 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use super::{
+    ApiKey, AuditEvent, AuthError, MfaSettings, OAuthState, PasswordResetToken, RefreshToken,
+    Session,
+};
+
+#[async_trait]
+pub trait Repository: Send + Sync {
+    async fn create_oauth_state(&self, oauth_state: &OAuthState) -> Result<OAuthState, AuthError>;
+    async fn take_oauth_state(&self, state: &str) -> Result<Option<OAuthState>, AuthError>;
+    async fn delete_expired_oauth_states(&self) -> Result<(), AuthError>;
+
+    async fn create_refresh_token(
+        &self,
+        refresh_token: &RefreshToken,
+    ) -> Result<RefreshToken, AuthError>;
+    async fn get_refresh_token_by_hash(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<RefreshToken>, AuthError>;
+    /// Marks the token as exchanged, returns `false` if it was already spent.
+    async fn rotate_refresh_token(&self, id: Uuid) -> Result<bool, AuthError>;
+
+    async fn create_session(&self, session: &Session) -> Result<Session, AuthError>;
+    async fn get_session(&self, id: Uuid) -> Result<Option<Session>, AuthError>;
+    async fn get_active_sessions_by_user(&self, user_id: i32) -> Result<Vec<Session>, AuthError>;
+    /// Updates `last_seen_at` and returns the session as it is now.
+    async fn touch_session(&self, id: Uuid) -> Result<Option<Session>, AuthError>;
+    /// Revokes the session together with its refresh token family.
+    async fn revoke_session(&self, id: Uuid) -> Result<(), AuthError>;
+
+    async fn revoke_token(
+        &self,
+        jti: &str,
+        user_id: i32,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), AuthError>;
+    async fn is_token_revoked(&self, jti: &str) -> Result<bool, AuthError>;
+    async fn delete_expired_revoked_tokens(&self) -> Result<(), AuthError>;
+    /// Rejects every access token issued up to `revoked_before` and revokes all
+    /// sessions and API keys.
+    async fn revoke_all_user_tokens(
+        &self,
+        user_id: i32,
+        revoked_before: DateTime<Utc>,
+    ) -> Result<(), AuthError>;
+    async fn get_user_tokens_revoked_before(
+        &self,
+        user_id: i32,
+    ) -> Result<Option<DateTime<Utc>>, AuthError>;
+
+    async fn create_password_reset_token(
+        &self,
+        reset_token: &PasswordResetToken,
+    ) -> Result<(), AuthError>;
+    /// Deletes and returns the token, so it can only be redeemed once.
+    async fn take_password_reset_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<PasswordResetToken>, AuthError>;
+    async fn delete_expired_password_reset_tokens(&self) -> Result<(), AuthError>;
+
+    /// Records the magic link as used, returns `false` if it already was.
+    async fn use_magic_link(&self, jti: &str, expires_at: DateTime<Utc>)
+        -> Result<bool, AuthError>;
+    async fn delete_expired_magic_links(&self) -> Result<(), AuthError>;
+
+    async fn get_mfa(&self, user_id: i32) -> Result<Option<MfaSettings>, AuthError>;
+    /// Starts a new, not yet enabled, enrollment replacing any previous one.
+    async fn save_mfa_secret(&self, user_id: i32, totp_secret: &str) -> Result<(), AuthError>;
+    /// Enables the enrollment and replaces the recovery codes.
+    async fn enable_mfa(
+        &self,
+        user_id: i32,
+        last_used_step: i64,
+        recovery_code_hashes: &[String],
+    ) -> Result<(), AuthError>;
+    /// Records the TOTP step as used, returns `false` if it or a later one already was.
+    async fn use_totp_step(&self, user_id: i32, step: i64) -> Result<bool, AuthError>;
+    /// Marks the recovery code as used, returns `false` if it is unknown or used.
+    async fn use_recovery_code(&self, user_id: i32, code_hash: &str) -> Result<bool, AuthError>;
+    async fn disable_mfa(&self, user_id: i32) -> Result<(), AuthError>;
+    /// Encrypts every TOTP secret that is in plain text or under an older key with
+    /// the active one, returns how many rows were rewritten.
+    async fn reencrypt_mfa_secrets(&self) -> Result<u64, AuthError>;
+    /// Counts one more code checked for the user, starting over when the last count
+    /// began before `window_start`. Returns the count, `None` without an enrollment.
+    async fn record_mfa_attempt(
+        &self,
+        user_id: i32,
+        window_start: DateTime<Utc>,
+    ) -> Result<Option<i32>, AuthError>;
+    async fn clear_mfa_attempts(&self, user_id: i32) -> Result<(), AuthError>;
+    /// Records the MFA pending token as used, returns `false` if it already was.
+    async fn use_mfa_pending_token(
+        &self,
+        jti: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<bool, AuthError>;
+    async fn delete_expired_mfa_pending_tokens(&self) -> Result<(), AuthError>;
+
+    async fn create_api_key(&self, api_key: &ApiKey) -> Result<ApiKey, AuthError>;
+    async fn get_api_key_by_prefix(&self, prefix: &str) -> Result<Option<ApiKey>, AuthError>;
+    /// Keys that were not revoked, including expired ones.
+    async fn get_api_keys_by_user(&self, user_id: i32) -> Result<Vec<ApiKey>, AuthError>;
+    /// Updates `last_used_at`, at most once a minute.
+    async fn touch_api_key(&self, id: Uuid) -> Result<(), AuthError>;
+    /// Returns `false` if the user has no such key that is not revoked yet.
+    async fn revoke_api_key(&self, id: Uuid, user_id: i32) -> Result<bool, AuthError>;
+
+    /// Stores the event, `id` and `created_at` are set by the database.
+    async fn create_audit_event(&self, event: &AuditEvent) -> Result<AuditEvent, AuthError>;
+    /// Newest first, optionally about one user and older than the event `before`.
+    async fn get_audit_events(
+        &self,
+        target_user_id: Option<i32>,
+        before: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<AuditEvent>, AuthError>;
+}
@@ -0,0 +1,66 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use super::{generate_token, hash_token};
+
+#[derive(Debug, Clone, FromRow)]
+pub struct RefreshToken {
+    pub id: Uuid,
+    pub family_id: Uuid,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub rotated_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl RefreshToken {
+    /// Creates a new token in `family_id` and returns it with the plain value,
+    /// which is only ever handed to the client and never stored.
+    pub fn generate(user_id: i32, family_id: Uuid, expiration_days: i64) -> (String, Self) {
+        let token = generate_token();
+
+        let created_at = Utc::now();
+        let refresh_token = RefreshToken {
+            id: Uuid::new_v4(),
+            family_id,
+            user_id,
+            token_hash: hash_token(&token),
+            created_at,
+            expires_at: created_at + Duration::days(expiration_days),
+            rotated_at: None,
+            revoked_at: None,
+        };
+        (token, refresh_token)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at < Utc::now()
+    }
+
+    /// A token that was already exchanged or revoked must never be presented again.
+    pub fn is_spent(&self) -> bool {
+        self.rotated_at.is_some() || self.revoked_at.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_token_matches_stored_hash() {
+        let family_id = Uuid::new_v4();
+        let (token, refresh_token) = RefreshToken::generate(1, family_id, 30);
+
+        assert_eq!(refresh_token.token_hash, hash_token(&token));
+        assert_ne!(
+            refresh_token.token_hash, token,
+            "Plain token must not be stored"
+        );
+        assert_eq!(refresh_token.family_id, family_id);
+        assert!(!refresh_token.is_expired() && !refresh_token.is_spent());
+    }
+}
@@ -0,0 +1,665 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::{
+    modules::auth::{
+        ports::Repository, ApiKey, AuditEvent, AuthError, MfaSettings, OAuthState,
+        PasswordResetToken, RefreshToken, Session,
+    },
+    utils::PostgresRepository,
+};
+
+/// Rows re-encrypted per transaction by `reencrypt_mfa_secrets`.
+const REENCRYPT_BATCH_SIZE: i64 = 100;
+
+/// Binds an encrypted TOTP secret to its user.
+fn mfa_secret_context(user_id: i32) -> String {
+    format!("user_mfa:{}", user_id)
+}
+
+#[async_trait]
+impl Repository for PostgresRepository {
+    async fn create_oauth_state(&self, oauth_state: &OAuthState) -> Result<OAuthState, AuthError> {
+        let query = "
+            INSERT INTO oauth_states (state, provider, pkce_verifier, created_at, expires_at, link_user_id)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING state, provider, pkce_verifier, created_at, expires_at, link_user_id";
+        sqlx::query_as::<_, OAuthState>(query)
+            .bind(&oauth_state.state)
+            .bind(&oauth_state.provider)
+            .bind(&oauth_state.pkce_verifier)
+            .bind(oauth_state.created_at)
+            .bind(oauth_state.expires_at)
+            .bind(oauth_state.link_user_id)
+            .fetch_one(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+    }
+
+    async fn take_oauth_state(&self, state: &str) -> Result<Option<OAuthState>, AuthError> {
+        let query = "
+            DELETE FROM oauth_states
+            WHERE state = $1
+            RETURNING state, provider, pkce_verifier, created_at, expires_at, link_user_id";
+        sqlx::query_as::<_, OAuthState>(query)
+            .bind(state)
+            .fetch_optional(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+    }
+
+    async fn delete_expired_oauth_states(&self) -> Result<(), AuthError> {
+        let query = "DELETE FROM oauth_states WHERE expires_at < NOW()";
+        sqlx::query(query)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+            .map(|_| ())
+    }
+
+    async fn create_refresh_token(
+        &self,
+        refresh_token: &RefreshToken,
+    ) -> Result<RefreshToken, AuthError> {
+        let query = "
+            INSERT INTO refresh_tokens (id, family_id, user_id, token_hash, created_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING *";
+        sqlx::query_as::<_, RefreshToken>(query)
+            .bind(refresh_token.id)
+            .bind(refresh_token.family_id)
+            .bind(refresh_token.user_id)
+            .bind(&refresh_token.token_hash)
+            .bind(refresh_token.created_at)
+            .bind(refresh_token.expires_at)
+            .fetch_one(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+    }
+
+    async fn get_refresh_token_by_hash(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<RefreshToken>, AuthError> {
+        let query = "SELECT * FROM refresh_tokens WHERE token_hash = $1";
+        sqlx::query_as::<_, RefreshToken>(query)
+            .bind(token_hash)
+            .fetch_optional(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+    }
+
+    async fn rotate_refresh_token(&self, id: Uuid) -> Result<bool, AuthError> {
+        let query = "
+            UPDATE refresh_tokens
+            SET rotated_at = NOW()
+            WHERE id = $1 AND rotated_at IS NULL AND revoked_at IS NULL";
+        sqlx::query(query)
+            .bind(id)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+            .map(|result| result.rows_affected() == 1)
+    }
+
+    async fn create_session(&self, session: &Session) -> Result<Session, AuthError> {
+        let query = "
+            INSERT INTO user_sessions (id, user_id, user_agent, ip_address, created_at, last_seen_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING *";
+        sqlx::query_as::<_, Session>(query)
+            .bind(session.id)
+            .bind(session.user_id)
+            .bind(&session.user_agent)
+            .bind(&session.ip_address)
+            .bind(session.created_at)
+            .bind(session.last_seen_at)
+            .fetch_one(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+    }
+
+    async fn get_session(&self, id: Uuid) -> Result<Option<Session>, AuthError> {
+        let query = "SELECT * FROM user_sessions WHERE id = $1";
+        sqlx::query_as::<_, Session>(query)
+            .bind(id)
+            .fetch_optional(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+    }
+
+    async fn get_active_sessions_by_user(&self, user_id: i32) -> Result<Vec<Session>, AuthError> {
+        let query = "
+            SELECT * FROM user_sessions
+            WHERE user_id = $1 AND revoked_at IS NULL
+            ORDER BY last_seen_at DESC";
+        sqlx::query_as::<_, Session>(query)
+            .bind(user_id)
+            .fetch_all(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+    }
+
+    async fn touch_session(&self, id: Uuid) -> Result<Option<Session>, AuthError> {
+        let query = "
+            UPDATE user_sessions
+            SET last_seen_at = NOW()
+            WHERE id = $1
+            RETURNING *";
+        sqlx::query_as::<_, Session>(query)
+            .bind(id)
+            .fetch_optional(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+    }
+
+    async fn revoke_session(&self, id: Uuid) -> Result<(), AuthError> {
+        let mut tx = self.pg_pool.begin().await?;
+
+        let query = "
+            UPDATE user_sessions
+            SET revoked_at = NOW()
+            WHERE id = $1 AND revoked_at IS NULL";
+        sqlx::query(query).bind(id).execute(&mut *tx).await?;
+
+        let query = "
+            UPDATE refresh_tokens
+            SET revoked_at = NOW()
+            WHERE family_id = $1 AND revoked_at IS NULL";
+        sqlx::query(query).bind(id).execute(&mut *tx).await?;
+
+        tx.commit().await.map_err(AuthError::from)
+    }
+
+    async fn revoke_token(
+        &self,
+        jti: &str,
+        user_id: i32,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), AuthError> {
+        let query = "
+            INSERT INTO revoked_tokens (jti, user_id, expires_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (jti) DO NOTHING";
+        sqlx::query(query)
+            .bind(jti)
+            .bind(user_id)
+            .bind(expires_at)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+            .map(|_| ())
+    }
+
+    async fn is_token_revoked(&self, jti: &str) -> Result<bool, AuthError> {
+        let query = "SELECT EXISTS(SELECT 1 FROM revoked_tokens WHERE jti = $1)";
+        sqlx::query_scalar::<_, bool>(query)
+            .bind(jti)
+            .fetch_one(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+    }
+
+    async fn delete_expired_revoked_tokens(&self) -> Result<(), AuthError> {
+        let query = "DELETE FROM revoked_tokens WHERE expires_at < NOW()";
+        sqlx::query(query)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+            .map(|_| ())
+    }
+
+    async fn revoke_all_user_tokens(
+        &self,
+        user_id: i32,
+        revoked_before: DateTime<Utc>,
+    ) -> Result<(), AuthError> {
+        let mut tx = self.pg_pool.begin().await?;
+
+        let query = "
+            INSERT INTO user_token_revocations (user_id, revoked_before)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET revoked_before = EXCLUDED.revoked_before";
+        sqlx::query(query)
+            .bind(user_id)
+            .bind(revoked_before)
+            .execute(&mut *tx)
+            .await?;
+
+        let query = "
+            UPDATE user_sessions
+            SET revoked_at = NOW()
+            WHERE user_id = $1 AND revoked_at IS NULL";
+        sqlx::query(query).bind(user_id).execute(&mut *tx).await?;
+
+        let query = "
+            UPDATE refresh_tokens
+            SET revoked_at = NOW()
+            WHERE user_id = $1 AND revoked_at IS NULL";
+        sqlx::query(query).bind(user_id).execute(&mut *tx).await?;
+
+        let query = "
+            UPDATE api_keys
+            SET revoked_at = NOW()
+            WHERE user_id = $1 AND revoked_at IS NULL";
+        sqlx::query(query).bind(user_id).execute(&mut *tx).await?;
+
+        tx.commit().await.map_err(AuthError::from)
+    }
+
+    async fn get_user_tokens_revoked_before(
+        &self,
+        user_id: i32,
+    ) -> Result<Option<DateTime<Utc>>, AuthError> {
+        let query = "SELECT revoked_before FROM user_token_revocations WHERE user_id = $1";
+        sqlx::query_scalar::<_, DateTime<Utc>>(query)
+            .bind(user_id)
+            .fetch_optional(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+    }
+
+    async fn create_password_reset_token(
+        &self,
+        reset_token: &PasswordResetToken,
+    ) -> Result<(), AuthError> {
+        let query = "
+            INSERT INTO password_reset_tokens (token_hash, user_id, created_at, expires_at)
+            VALUES ($1, $2, $3, $4)";
+        sqlx::query(query)
+            .bind(&reset_token.token_hash)
+            .bind(reset_token.user_id)
+            .bind(reset_token.created_at)
+            .bind(reset_token.expires_at)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+            .map(|_| ())
+    }
+
+    async fn take_password_reset_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<PasswordResetToken>, AuthError> {
+        let query = "
+            DELETE FROM password_reset_tokens
+            WHERE token_hash = $1
+            RETURNING *";
+        sqlx::query_as::<_, PasswordResetToken>(query)
+            .bind(token_hash)
+            .fetch_optional(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+    }
+
+    async fn delete_expired_password_reset_tokens(&self) -> Result<(), AuthError> {
+        let query = "DELETE FROM password_reset_tokens WHERE expires_at < NOW()";
+        sqlx::query(query)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+            .map(|_| ())
+    }
+
+    async fn use_magic_link(
+        &self,
+        jti: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<bool, AuthError> {
+        let query = "
+            INSERT INTO used_magic_links (jti, expires_at)
+            VALUES ($1, $2)
+            ON CONFLICT (jti) DO NOTHING";
+        sqlx::query(query)
+            .bind(jti)
+            .bind(expires_at)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+            .map(|result| result.rows_affected() == 1)
+    }
+
+    async fn delete_expired_magic_links(&self) -> Result<(), AuthError> {
+        let query = "DELETE FROM used_magic_links WHERE expires_at < NOW()";
+        sqlx::query(query)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+            .map(|_| ())
+    }
+
+    async fn get_mfa(&self, user_id: i32) -> Result<Option<MfaSettings>, AuthError> {
+        let query = "SELECT * FROM user_mfa WHERE user_id = $1";
+        let settings = sqlx::query_as::<_, MfaSettings>(query)
+            .bind(user_id)
+            .fetch_optional(&*self.pg_pool)
+            .await?;
+        settings
+            .map(|settings| {
+                Ok(MfaSettings {
+                    totp_secret: self
+                        .token_cipher
+                        .decrypt(&settings.totp_secret, &mfa_secret_context(user_id))?,
+                    ..settings
+                })
+            })
+            .transpose()
+    }
+
+    async fn save_mfa_secret(&self, user_id: i32, totp_secret: &str) -> Result<(), AuthError> {
+        let query = "
+            INSERT INTO user_mfa (user_id, totp_secret)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE
+            SET totp_secret = EXCLUDED.totp_secret,
+                enabled_at = NULL,
+                last_used_step = NULL,
+                created_at = NOW()";
+        sqlx::query(query)
+            .bind(user_id)
+            .bind(
+                self.token_cipher
+                    .encrypt(totp_secret, &mfa_secret_context(user_id)),
+            )
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+            .map(|_| ())
+    }
+
+    async fn enable_mfa(
+        &self,
+        user_id: i32,
+        last_used_step: i64,
+        recovery_code_hashes: &[String],
+    ) -> Result<(), AuthError> {
+        let mut tx = self.pg_pool.begin().await?;
+
+        let query = "
+            UPDATE user_mfa
+            SET enabled_at = NOW(), last_used_step = $2
+            WHERE user_id = $1";
+        sqlx::query(query)
+            .bind(user_id)
+            .bind(last_used_step)
+            .execute(&mut *tx)
+            .await?;
+
+        let query = "DELETE FROM mfa_recovery_codes WHERE user_id = $1";
+        sqlx::query(query).bind(user_id).execute(&mut *tx).await?;
+
+        let query = "
+            INSERT INTO mfa_recovery_codes (user_id, code_hash)
+            SELECT $1, UNNEST($2::VARCHAR[])";
+        sqlx::query(query)
+            .bind(user_id)
+            .bind(recovery_code_hashes)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await.map_err(AuthError::from)
+    }
+
+    async fn use_totp_step(&self, user_id: i32, step: i64) -> Result<bool, AuthError> {
+        let query = "
+            UPDATE user_mfa
+            SET last_used_step = $2
+            WHERE user_id = $1 AND (last_used_step IS NULL OR last_used_step < $2)";
+        sqlx::query(query)
+            .bind(user_id)
+            .bind(step)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+            .map(|result| result.rows_affected() == 1)
+    }
+
+    async fn use_recovery_code(&self, user_id: i32, code_hash: &str) -> Result<bool, AuthError> {
+        let query = "
+            UPDATE mfa_recovery_codes
+            SET used_at = NOW()
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL";
+        sqlx::query(query)
+            .bind(user_id)
+            .bind(code_hash)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+            .map(|result| result.rows_affected() > 0)
+    }
+
+    async fn disable_mfa(&self, user_id: i32) -> Result<(), AuthError> {
+        let mut tx = self.pg_pool.begin().await?;
+
+        let query = "DELETE FROM mfa_recovery_codes WHERE user_id = $1";
+        sqlx::query(query).bind(user_id).execute(&mut *tx).await?;
+
+        let query = "DELETE FROM user_mfa WHERE user_id = $1";
+        sqlx::query(query).bind(user_id).execute(&mut *tx).await?;
+
+        tx.commit().await.map_err(AuthError::from)
+    }
+
+    async fn reencrypt_mfa_secrets(&self) -> Result<u64, AuthError> {
+        let current_prefix = self.token_cipher.current_prefix();
+        let mut reencrypted = 0;
+        let mut last_user_id = 0;
+        loop {
+            let mut tx = self.pg_pool.begin().await?;
+
+            // SKIP LOCKED lets several instances run it at the same time
+            let query = "
+                SELECT user_id, totp_secret FROM user_mfa
+                WHERE NOT starts_with(totp_secret, $1) AND user_id > $2
+                ORDER BY user_id
+                LIMIT $3
+                FOR UPDATE SKIP LOCKED";
+            let rows = sqlx::query_as::<_, (i32, String)>(query)
+                .bind(&current_prefix)
+                .bind(last_user_id)
+                .bind(REENCRYPT_BATCH_SIZE)
+                .fetch_all(&mut *tx)
+                .await?;
+            let Some((user_id, _)) = rows.last() else {
+                return Ok(reencrypted);
+            };
+            last_user_id = *user_id;
+
+            for (user_id, totp_secret) in rows {
+                let context = mfa_secret_context(user_id);
+                // A row that cannot be decrypted is left as it is for someone to look at
+                let plaintext = match self.token_cipher.decrypt(&totp_secret, &context) {
+                    Ok(plaintext) => plaintext,
+                    Err(err) => {
+                        log::warn!(
+                            "Skipped re-encrypting the MFA secret of user {}: {}",
+                            user_id,
+                            err
+                        );
+                        continue;
+                    }
+                };
+                let query = "UPDATE user_mfa SET totp_secret = $1 WHERE user_id = $2";
+                sqlx::query(query)
+                    .bind(self.token_cipher.encrypt(&plaintext, &context))
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+                reencrypted += 1;
+            }
+
+            tx.commit().await?;
+        }
+    }
+
+    async fn record_mfa_attempt(
+        &self,
+        user_id: i32,
+        window_start: DateTime<Utc>,
+    ) -> Result<Option<i32>, AuthError> {
+        let query = "
+            UPDATE user_mfa
+            SET failed_attempts = CASE
+                    WHEN failed_attempts_since >= $2 THEN failed_attempts + 1
+                    ELSE 1
+                END,
+                failed_attempts_since = CASE
+                    WHEN failed_attempts_since >= $2 THEN failed_attempts_since
+                    ELSE NOW()
+                END
+            WHERE user_id = $1
+            RETURNING failed_attempts";
+        sqlx::query_scalar(query)
+            .bind(user_id)
+            .bind(window_start)
+            .fetch_optional(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+    }
+
+    async fn clear_mfa_attempts(&self, user_id: i32) -> Result<(), AuthError> {
+        let query = "
+            UPDATE user_mfa
+            SET failed_attempts = 0, failed_attempts_since = NULL
+            WHERE user_id = $1";
+        sqlx::query(query)
+            .bind(user_id)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+            .map(|_| ())
+    }
+
+    async fn use_mfa_pending_token(
+        &self,
+        jti: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<bool, AuthError> {
+        let query = "
+            INSERT INTO used_mfa_pending_tokens (jti, expires_at)
+            VALUES ($1, $2)
+            ON CONFLICT (jti) DO NOTHING";
+        sqlx::query(query)
+            .bind(jti)
+            .bind(expires_at)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+            .map(|result| result.rows_affected() == 1)
+    }
+
+    async fn delete_expired_mfa_pending_tokens(&self) -> Result<(), AuthError> {
+        let query = "DELETE FROM used_mfa_pending_tokens WHERE expires_at < NOW()";
+        sqlx::query(query)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+            .map(|_| ())
+    }
+
+    async fn create_api_key(&self, api_key: &ApiKey) -> Result<ApiKey, AuthError> {
+        let query = "
+            INSERT INTO api_keys (id, user_id, name, prefix, key_hash, scopes, created_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING *";
+        sqlx::query_as::<_, ApiKey>(query)
+            .bind(api_key.id)
+            .bind(api_key.user_id)
+            .bind(&api_key.name)
+            .bind(&api_key.prefix)
+            .bind(&api_key.key_hash)
+            .bind(&api_key.scopes)
+            .bind(api_key.created_at)
+            .bind(api_key.expires_at)
+            .fetch_one(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+    }
+
+    async fn get_api_key_by_prefix(&self, prefix: &str) -> Result<Option<ApiKey>, AuthError> {
+        let query = "SELECT * FROM api_keys WHERE prefix = $1";
+        sqlx::query_as::<_, ApiKey>(query)
+            .bind(prefix)
+            .fetch_optional(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+    }
+
+    async fn get_api_keys_by_user(&self, user_id: i32) -> Result<Vec<ApiKey>, AuthError> {
+        let query = "
+            SELECT * FROM api_keys
+            WHERE user_id = $1 AND revoked_at IS NULL
+            ORDER BY created_at DESC";
+        sqlx::query_as::<_, ApiKey>(query)
+            .bind(user_id)
+            .fetch_all(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+    }
+
+    async fn touch_api_key(&self, id: Uuid) -> Result<(), AuthError> {
+        let query = "
+            UPDATE api_keys
+            SET last_used_at = NOW()
+            WHERE id = $1
+              AND (last_used_at IS NULL OR last_used_at < NOW() - INTERVAL '1 minute')";
+        sqlx::query(query)
+            .bind(id)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+            .map(|_| ())
+    }
+
+    async fn revoke_api_key(&self, id: Uuid, user_id: i32) -> Result<bool, AuthError> {
+        let query = "
+            UPDATE api_keys
+            SET revoked_at = NOW()
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL";
+        sqlx::query(query)
+            .bind(id)
+            .bind(user_id)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+            .map(|result| result.rows_affected() == 1)
+    }
+
+    async fn create_audit_event(&self, event: &AuditEvent) -> Result<AuditEvent, AuthError> {
+        let query = "
+            INSERT INTO audit_log (actor_user_id, action, target_user_id, reason, ip_address)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING *";
+        sqlx::query_as::<_, AuditEvent>(query)
+            .bind(event.actor_user_id)
+            .bind(&event.action)
+            .bind(event.target_user_id)
+            .bind(&event.reason)
+            .bind(&event.ip_address)
+            .fetch_one(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+    }
+
+    async fn get_audit_events(
+        &self,
+        target_user_id: Option<i32>,
+        before: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<AuditEvent>, AuthError> {
+        let query = "
+            SELECT * FROM audit_log
+            WHERE ($1::INTEGER IS NULL OR target_user_id = $1)
+              AND ($2::BIGINT IS NULL OR id < $2)
+            ORDER BY id DESC
+            LIMIT $3";
+        sqlx::query_as::<_, AuditEvent>(query)
+            .bind(target_user_id)
+            .bind(before)
+            .bind(limit)
+            .fetch_all(&*self.pg_pool)
+            .await
+            .map_err(AuthError::from)
+    }
+}
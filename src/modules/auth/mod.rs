@@ -1,6 +1,11 @@
 mod domain;
 pub use domain::*;
 
+mod app_service;
+pub use app_service::*;
+
 pub mod api;
 
+pub mod infrastructure;
+
 pub mod provider;
@@ -0,0 +1,173 @@
+use async_trait::async_trait;
+use oauth2::{
+    basic::{BasicClient, BasicTokenType},
+    reqwest::async_http_client,
+    AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, EmptyExtraTokenFields,
+    PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, StandardTokenResponse, TokenResponse,
+    TokenUrl,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use crate::{
+    modules::auth::{
+        provider::{AuthorizationRequest, OAuthProvider},
+        AuthError, OAuthData, OAuthProviderType,
+    },
+    utils::Config,
+};
+
+const GITHUB_API_URL: &str = "https://api.github.com";
+// GitHub rejects API requests without a User-Agent header
+const USER_AGENT: &str = "user_oauth_stripe_skeleton";
+
+#[derive(Debug, Deserialize)]
+struct GithubUser {
+    id: u64,
+    login: String,
+    name: Option<String>,
+    avatar_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+pub struct Provider {
+    oauth_client: Arc<BasicClient>,
+}
+
+impl Provider {
+    pub fn new() -> Self {
+        let config = Config::from_env();
+
+        let github_client_id = config.github_client_id.expect("GITHUB_CLIENT_ID not set");
+        let github_client_secret = config
+            .github_client_secret
+            .expect("GITHUB_CLIENT_SECRET not set");
+        let github_redirect_uri = config
+            .github_redirect_uri
+            .expect("GITHUB_REDIRECT_URI not set");
+        let client = BasicClient::new(
+            ClientId::new(github_client_id),
+            Some(ClientSecret::new(github_client_secret)),
+            AuthUrl::new("https://github.com/login/oauth/authorize".to_owned())
+                .expect("Invalid authorization endpoint URL"),
+            Some(
+                TokenUrl::new("https://github.com/login/oauth/access_token".to_owned())
+                    .expect("Invalid token endpoint URL"),
+            ),
+        )
+        .set_redirect_uri(RedirectUrl::new(github_redirect_uri).expect("Invalid redirect URI"));
+        Self {
+            oauth_client: Arc::new(client),
+        }
+    }
+
+    async fn exchange_token(
+        &self,
+        code: String,
+        pkce_verifier: PkceCodeVerifier,
+    ) -> Result<StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>, AuthError> {
+        self.oauth_client
+            .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(pkce_verifier)
+            .request_async(async_http_client)
+            .await
+            .map_err(|err| AuthError::OAuth2RequestTokenError(err.to_string()))
+    }
+
+    async fn fetch_github<T: for<'de> Deserialize<'de>>(
+        &self,
+        path: &str,
+        access_token: &str,
+    ) -> Result<T, AuthError> {
+        let client = reqwest::Client::new();
+        Ok(client
+            .get(format!("{}{}", GITHUB_API_URL, path))
+            .bearer_auth(access_token)
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<T>()
+            .await?)
+    }
+
+    fn extract_oauth_data(
+        &self,
+        token_response: &StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>,
+        github_user: GithubUser,
+        emails: Vec<GithubEmail>,
+    ) -> Result<OAuthData, AuthError> {
+        // Classic OAuth app tokens never expire and come without a refresh token,
+        // in that case the access token itself is the long-lived credential.
+        let refresh_token = token_response
+            .refresh_token()
+            .map(|token| token.secret().to_string())
+            .unwrap_or_else(|| token_response.access_token().secret().to_string());
+
+        let email = emails
+            .into_iter()
+            .find(|email| email.primary && email.verified)
+            .map(|email| email.email)
+            .ok_or(AuthError::EmailNotVerified)?;
+
+        Ok(OAuthData {
+            provider: OAuthProviderType::Github,
+            user_identifier: github_user.id.to_string(),
+            name: github_user.name.unwrap_or(github_user.login),
+            email,
+            email_verified: true,
+            refresh_token,
+            image_url: github_user.avatar_url,
+        })
+    }
+}
+
+#[async_trait]
+impl OAuthProvider for Provider {
+    fn provider_type(&self) -> OAuthProviderType {
+        OAuthProviderType::Github
+    }
+
+    async fn get_authorization_url(&self) -> AuthorizationRequest {
+        let scopes = ["read:user", "user:email"];
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+
+        let (auth_url, csrf_state) = scopes
+            .iter()
+            .fold(
+                self.oauth_client.authorize_url(CsrfToken::new_random),
+                |url, scope| url.add_scope(Scope::new(scope.to_string())),
+            )
+            .set_pkce_challenge(pkce_challenge)
+            .url();
+
+        AuthorizationRequest {
+            url: auth_url.to_string(),
+            csrf_token: csrf_state,
+            pkce_verifier,
+        }
+    }
+
+    async fn handle_oauth_callback(
+        &self,
+        code: String,
+        pkce_verifier: PkceCodeVerifier,
+    ) -> Result<OAuthData, AuthError> {
+        let token_response = self.exchange_token(code, pkce_verifier).await?;
+        let access_token = token_response.access_token().secret();
+        let github_user = self
+            .fetch_github::<GithubUser>("/user", access_token)
+            .await?;
+        let emails = self
+            .fetch_github::<Vec<GithubEmail>>("/user/emails", access_token)
+            .await?;
+        self.extract_oauth_data(&token_response, github_user, emails)
+    }
+}
@@ -3,30 +3,26 @@ use oauth2::{
     basic::{BasicClient, BasicTokenType},
     reqwest::async_http_client,
     AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, EmptyExtraTokenFields,
-    RedirectUrl, Scope, StandardTokenResponse, TokenResponse, TokenUrl,
+    PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, StandardTokenResponse, TokenResponse,
+    TokenUrl,
 };
 use serde_json::Value;
 use std::sync::Arc;
 
 use crate::{
-    error::ApiError,
-    modules::{
-        auth::{
-            create_jwt, provider::OAuthProvider, AuthError, OAuthData, OAuthProviderType,
-            OAuthResponse,
-        },
-        user,
+    modules::auth::{
+        provider::{AuthorizationRequest, OAuthProvider},
+        AuthError, OAuthData, OAuthProviderType,
     },
     utils::Config,
 };
 
 pub struct Provider {
     oauth_client: Arc<BasicClient>,
-    user_service: Arc<user::Service>,
 }
 
 impl Provider {
-    pub fn new(user_service: Arc<user::Service>) -> Self {
+    pub fn new() -> Self {
         let config = Config::from_env();
 
         let google_client_id = config.google_client_id;
@@ -47,16 +43,17 @@ impl Provider {
         );
         Self {
             oauth_client: Arc::new(client),
-            user_service,
         }
     }
 
     async fn exchange_token(
         &self,
         code: String,
+        pkce_verifier: PkceCodeVerifier,
     ) -> Result<StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>, AuthError> {
         self.oauth_client
             .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(pkce_verifier)
             .request_async(async_http_client)
             .await
             .map_err(|err| AuthError::OAuth2RequestTokenError(err.to_string()))
@@ -88,6 +85,10 @@ impl Provider {
         let user_identifier = extract_field(user_info, "sub")?;
         let name = extract_field(user_info, "given_name")?;
         let email = extract_field(user_info, "email")?;
+        let email_verified = user_info
+            .get("email_verified")
+            .and_then(|val| val.as_bool())
+            .unwrap_or(false);
         let image_url = user_info.get("picture").and_then(|val| val.as_str());
 
         Ok(OAuthData {
@@ -95,6 +96,7 @@ impl Provider {
             user_identifier,
             name,
             email,
+            email_verified,
             refresh_token,
             image_url: image_url.map(|url| url.to_string()),
         })
@@ -103,8 +105,13 @@ impl Provider {
 
 #[async_trait]
 impl OAuthProvider for Provider {
-    async fn get_authorization_url(&self) -> (String, CsrfToken) {
+    fn provider_type(&self) -> OAuthProviderType {
+        OAuthProviderType::Google
+    }
+
+    async fn get_authorization_url(&self) -> AuthorizationRequest {
         let scopes = vec!["email", "profile", "openid"];
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
         let (auth_url, csrf_state) = scopes
             .iter()
@@ -112,22 +119,28 @@ impl OAuthProvider for Provider {
                 self.oauth_client.authorize_url(CsrfToken::new_random),
                 |url, scope| url.add_scope(Scope::new(scope.to_string())),
             )
+            .set_pkce_challenge(pkce_challenge)
             .add_extra_param("access_type", "offline")
             .add_extra_param("prompt", "consent")
             .url();
 
-        (auth_url.to_string(), csrf_state)
+        AuthorizationRequest {
+            url: auth_url.to_string(),
+            csrf_token: csrf_state,
+            pkce_verifier,
+        }
     }
 
-    async fn handle_oauth_callback(&self, code: String) -> Result<OAuthResponse, ApiError> {
-        let token_response = self.exchange_token(code).await?;
+    async fn handle_oauth_callback(
+        &self,
+        code: String,
+        pkce_verifier: PkceCodeVerifier,
+    ) -> Result<OAuthData, AuthError> {
+        let token_response = self.exchange_token(code, pkce_verifier).await?;
         let user_info = self
             .fetch_google_user_info(token_response.access_token().secret())
             .await?;
-        let oauth_data = self.extract_oauth_data(&token_response, &user_info)?;
-        let user = self.user_service.sign_up_or_login(oauth_data).await?;
-        let token = create_jwt(&user)?;
-        Ok(OAuthResponse { user, token })
+        self.extract_oauth_data(&token_response, &user_info)
     }
 }
 
@@ -1,4 +1,9 @@
+pub mod github;
 pub mod google;
+pub mod oidc;
 
 mod provider;
 pub use provider::*;
+
+mod registry;
+pub use registry::*;
@@ -0,0 +1,275 @@
+use std::time::{Duration, Instant};
+
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use tokio::sync::RwLock;
+
+use crate::modules::auth::AuthError;
+
+/// Unknown `kid`s trigger a JWKS refetch, but never more often than this.
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const ID_TOKEN_LEEWAY_SECONDS: u64 = 60;
+
+/// The subset of `.well-known/openid-configuration` we rely on.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+impl ProviderMetadata {
+    pub async fn discover(issuer_url: &str) -> Result<Self, AuthError> {
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer_url.trim_end_matches('/')
+        );
+        let metadata = reqwest::get(discovery_url)
+            .await?
+            .error_for_status()?
+            .json::<ProviderMetadata>()
+            .await?;
+
+        if metadata.issuer.trim_end_matches('/') != issuer_url.trim_end_matches('/') {
+            return Err(AuthError::InvalidTokenError(format!(
+                "Discovery document issuer '{}' does not match '{}'",
+                metadata.issuer, issuer_url
+            )));
+        }
+
+        Ok(metadata)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub name: Option<String>,
+    pub given_name: Option<String>,
+    pub preferred_username: Option<String>,
+    pub picture: Option<String>,
+}
+
+/// Validates `id_token`s against the issuer's JWKS, refetching the key set
+/// when the issuer rotates its signing keys.
+pub struct IdTokenVerifier {
+    issuer: String,
+    client_id: String,
+    jwks_uri: String,
+    jwks: RwLock<(JwkSet, Instant)>,
+}
+
+impl IdTokenVerifier {
+    pub async fn new(metadata: &ProviderMetadata, client_id: &str) -> Result<Self, AuthError> {
+        let jwks = fetch_jwks(&metadata.jwks_uri).await?;
+        Ok(Self {
+            issuer: metadata.issuer.clone(),
+            client_id: client_id.to_string(),
+            jwks_uri: metadata.jwks_uri.clone(),
+            jwks: RwLock::new((jwks, Instant::now())),
+        })
+    }
+
+    pub async fn verify(&self, id_token: &str) -> Result<IdTokenClaims, AuthError> {
+        let header = decode_header(id_token)?;
+        if !matches!(
+            header.alg,
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512
+        ) {
+            return Err(AuthError::InvalidTokenError(format!(
+                "Unsupported id_token algorithm {:?}",
+                header.alg
+            )));
+        }
+
+        let jwk = match self.find_key(header.kid.as_deref()).await {
+            Some(jwk) => jwk,
+            None => {
+                self.refresh_jwks().await?;
+                self.find_key(header.kid.as_deref()).await.ok_or_else(|| {
+                    AuthError::InvalidTokenError("Unknown id_token signing key".to_string())
+                })?
+            }
+        };
+
+        let (n, e) =
+            jwk.n.as_deref().zip(jwk.e.as_deref()).ok_or_else(|| {
+                AuthError::InvalidTokenError("Invalid RSA key in JWKS".to_string())
+            })?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = ID_TOKEN_LEEWAY_SECONDS;
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.client_id]);
+
+        decode::<IdTokenClaims>(
+            id_token,
+            &DecodingKey::from_rsa_components(n, e)?,
+            &validation,
+        )
+        .map(|data| data.claims)
+        .map_err(AuthError::JwtError)
+    }
+
+    async fn find_key(&self, kid: Option<&str>) -> Option<Jwk> {
+        let jwks = self.jwks.read().await;
+        let mut rsa_keys = jwks.0.keys.iter().filter(|key| key.kty == "RSA");
+        match kid {
+            Some(kid) => rsa_keys
+                .find(|key| key.kid.as_deref() == Some(kid))
+                .cloned(),
+            // Without a kid the key is only unambiguous if the issuer publishes a single one
+            None => match (rsa_keys.next(), rsa_keys.next()) {
+                (Some(key), None) => Some(key.clone()),
+                _ => None,
+            },
+        }
+    }
+
+    async fn refresh_jwks(&self) -> Result<(), AuthError> {
+        let mut jwks = self.jwks.write().await;
+        if jwks.1.elapsed() < JWKS_REFRESH_INTERVAL {
+            return Ok(());
+        }
+        *jwks = (fetch_jwks(&self.jwks_uri).await?, Instant::now());
+        Ok(())
+    }
+}
+
+async fn fetch_jwks(jwks_uri: &str) -> Result<JwkSet, AuthError> {
+    Ok(reqwest::get(jwks_uri)
+        .await?
+        .error_for_status()?
+        .json::<JwkSet>()
+        .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use chrono::Utc;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+
+    const TEST_KEY: &[u8] = include_bytes!("test_rsa_key.pem");
+    const TEST_KEY_MODULUS: &str = "j8srzVkz9gsMie5_5E0RWtUqP5lJVVE-Rg4NTmkyx8xCLJowdijrcK9hy5b6HVAAWC1KjPvagIHBngBH2tsWBYELTslKLrBlfaFNS4R7lzU-3S2DuVYhlw4QvZdWZ7aqSjQjz9bRRLwYDsMdB18TBgDFP-ebW_WatdUixVR2mqjUahDPwiyIDVGs2qBgwrtFqtFCbf-4NItfxJeaNAT1vv2EEJ7PLhsUkSjIRVJLTRiEfCRG9MXBtgmoj2Fyr1wFCs7f_7BOfY4pydRSU0aZsPpxHWTR8g3sZg8JYtWcwvUEpKcedZg_kCsJw28WglBbc1w2x-vsFlIsBoacLK9EyQ";
+    const CLIENT_ID: &str = "test-client";
+
+    // Serves a discovery document and JWKS like a real issuer would
+    async fn start_stub_issuer() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+
+        let discovery = json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+        });
+        let jwks = json!({
+            "keys": [{ "kty": "RSA", "kid": "test-key", "alg": "RS256", "use": "sig", "n": TEST_KEY_MODULUS, "e": "AQAB" }]
+        });
+
+        let server = HttpServer::new(move || {
+            let discovery = discovery.clone();
+            let jwks = jwks.clone();
+            App::new()
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to(move || {
+                        let discovery = discovery.clone();
+                        async move { HttpResponse::Ok().json(discovery) }
+                    }),
+                )
+                .route(
+                    "/jwks",
+                    web::get().to(move || {
+                        let jwks = jwks.clone();
+                        async move { HttpResponse::Ok().json(jwks) }
+                    }),
+                )
+        })
+        .listen(listener)
+        .unwrap()
+        .workers(1)
+        .run();
+        actix_web::rt::spawn(server);
+
+        issuer
+    }
+
+    fn sign_id_token(issuer: &str, audience: &str, kid: &str) -> String {
+        let claims = json!({
+            "iss": issuer,
+            "aud": audience,
+            "sub": "user-1",
+            "email": "test@example.com",
+            "email_verified": true,
+            "exp": (Utc::now() + chrono::Duration::minutes(5)).timestamp(),
+        });
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(kid.to_string());
+        encode(
+            &header,
+            &claims,
+            &EncodingKey::from_rsa_pem(TEST_KEY).unwrap(),
+        )
+        .unwrap()
+    }
+
+    async fn verifier_for(issuer: &str) -> IdTokenVerifier {
+        let metadata = ProviderMetadata::discover(issuer)
+            .await
+            .expect("Failed to discover stub issuer");
+        IdTokenVerifier::new(&metadata, CLIENT_ID)
+            .await
+            .expect("Failed to fetch stub JWKS")
+    }
+
+    #[actix_web::test]
+    async fn test_verify_id_token_from_stub_issuer() {
+        let issuer = start_stub_issuer().await;
+        let verifier = verifier_for(&issuer).await;
+
+        let claims = verifier
+            .verify(&sign_id_token(&issuer, CLIENT_ID, "test-key"))
+            .await
+            .expect("Failed to verify id_token");
+
+        assert_eq!(claims.sub, "user-1");
+        assert_eq!(claims.email.as_deref(), Some("test@example.com"));
+        assert_eq!(claims.email_verified, Some(true));
+    }
+
+    #[actix_web::test]
+    async fn test_reject_id_token_for_other_audience_or_key() {
+        let issuer = start_stub_issuer().await;
+        let verifier = verifier_for(&issuer).await;
+
+        let other_audience = sign_id_token(&issuer, "other-client", "test-key");
+        assert!(verifier.verify(&other_audience).await.is_err());
+
+        let unknown_key = sign_id_token(&issuer, CLIENT_ID, "rotated-key");
+        assert!(verifier.verify(&unknown_key).await.is_err());
+    }
+}
@@ -0,0 +1,5 @@
+mod id_token;
+pub use id_token::*;
+
+mod oidc;
+pub use oidc::*;
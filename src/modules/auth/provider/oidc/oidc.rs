@@ -0,0 +1,160 @@
+use async_trait::async_trait;
+use oauth2::{
+    basic::{
+        BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
+        BasicTokenType,
+    },
+    reqwest::async_http_client,
+    AuthUrl, AuthorizationCode, Client, ClientId, ClientSecret, CsrfToken, ExtraTokenFields,
+    PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, StandardRevocableToken,
+    StandardTokenResponse, TokenResponse, TokenUrl,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    modules::auth::{
+        provider::{AuthorizationRequest, OAuthProvider},
+        AuthError, OAuthData, OAuthProviderType,
+    },
+    utils::OidcProviderConfig,
+};
+
+use super::{IdTokenClaims, IdTokenVerifier, ProviderMetadata};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdTokenFields {
+    pub id_token: String,
+}
+impl ExtraTokenFields for IdTokenFields {}
+
+type OidcTokenResponse = StandardTokenResponse<IdTokenFields, BasicTokenType>;
+
+type OidcClient = Client<
+    BasicErrorResponse,
+    OidcTokenResponse,
+    BasicTokenType,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+>;
+
+/// OpenID Connect provider configured entirely from the issuer's discovery
+/// document, e.g. Keycloak, Okta, Azure AD or Auth0.
+pub struct Provider {
+    name: String,
+    oauth_client: Arc<OidcClient>,
+    id_token_verifier: IdTokenVerifier,
+}
+
+impl Provider {
+    pub async fn discover(config: OidcProviderConfig) -> Result<Self, AuthError> {
+        let metadata = ProviderMetadata::discover(&config.issuer_url).await?;
+        let id_token_verifier = IdTokenVerifier::new(&metadata, &config.client_id).await?;
+
+        let client = OidcClient::new(
+            ClientId::new(config.client_id),
+            Some(ClientSecret::new(config.client_secret)),
+            AuthUrl::new(metadata.authorization_endpoint)
+                .map_err(|err| AuthError::InvalidTokenError(err.to_string()))?,
+            Some(
+                TokenUrl::new(metadata.token_endpoint)
+                    .map_err(|err| AuthError::InvalidTokenError(err.to_string()))?,
+            ),
+        )
+        .set_redirect_uri(
+            RedirectUrl::new(config.redirect_uri)
+                .map_err(|err| AuthError::InvalidTokenError(err.to_string()))?,
+        );
+
+        Ok(Self {
+            name: config.name,
+            oauth_client: Arc::new(client),
+            id_token_verifier,
+        })
+    }
+
+    async fn exchange_token(
+        &self,
+        code: String,
+        pkce_verifier: PkceCodeVerifier,
+    ) -> Result<OidcTokenResponse, AuthError> {
+        self.oauth_client
+            .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(pkce_verifier)
+            .request_async(async_http_client)
+            .await
+            .map_err(|err| AuthError::OAuth2RequestTokenError(err.to_string()))
+    }
+
+    fn extract_oauth_data(
+        &self,
+        token_response: &OidcTokenResponse,
+        claims: IdTokenClaims,
+    ) -> Result<OAuthData, AuthError> {
+        // Not every issuer hands out refresh tokens, e.g. without the offline_access scope
+        let refresh_token = token_response
+            .refresh_token()
+            .map(|token| token.secret().to_string())
+            .unwrap_or_default();
+
+        let email = claims.email.ok_or_else(|| {
+            AuthError::InvalidTokenError("Missing 'email' claim in id_token".to_string())
+        })?;
+        let name = claims
+            .name
+            .or(claims.given_name)
+            .or(claims.preferred_username)
+            .unwrap_or_else(|| email.clone());
+
+        Ok(OAuthData {
+            provider: self.provider_type(),
+            user_identifier: claims.sub,
+            name,
+            email,
+            email_verified: claims.email_verified == Some(true),
+            refresh_token,
+            image_url: claims.picture,
+        })
+    }
+}
+
+#[async_trait]
+impl OAuthProvider for Provider {
+    fn provider_type(&self) -> OAuthProviderType {
+        OAuthProviderType::Oidc(self.name.clone())
+    }
+
+    async fn get_authorization_url(&self) -> AuthorizationRequest {
+        let scopes = ["openid", "email", "profile"];
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+
+        let (auth_url, csrf_state) = scopes
+            .iter()
+            .fold(
+                self.oauth_client.authorize_url(CsrfToken::new_random),
+                |url, scope| url.add_scope(Scope::new(scope.to_string())),
+            )
+            .set_pkce_challenge(pkce_challenge)
+            .url();
+
+        AuthorizationRequest {
+            url: auth_url.to_string(),
+            csrf_token: csrf_state,
+            pkce_verifier,
+        }
+    }
+
+    async fn handle_oauth_callback(
+        &self,
+        code: String,
+        pkce_verifier: PkceCodeVerifier,
+    ) -> Result<OAuthData, AuthError> {
+        let token_response = self.exchange_token(code, pkce_verifier).await?;
+        let claims = self
+            .id_token_verifier
+            .verify(&token_response.extra_fields().id_token)
+            .await?;
+        self.extract_oauth_data(&token_response, claims)
+    }
+}
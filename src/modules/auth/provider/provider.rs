@@ -1,11 +1,25 @@
 use async_trait::async_trait;
-use oauth2::CsrfToken;
+use oauth2::{CsrfToken, PkceCodeVerifier};
 
-use crate::{error::ApiError, modules::auth::OAuthResponse};
+use crate::modules::auth::{AuthError, OAuthData, OAuthProviderType};
+
+/// Everything the caller needs to send the user to the provider and later
+/// redeem the authorization code.
+pub struct AuthorizationRequest {
+    pub url: String,
+    pub csrf_token: CsrfToken,
+    pub pkce_verifier: PkceCodeVerifier,
+}
 
 #[async_trait]
 pub trait OAuthProvider: Send + Sync {
-    async fn get_authorization_url(&self) -> (String, CsrfToken);
+    fn provider_type(&self) -> OAuthProviderType;
+
+    async fn get_authorization_url(&self) -> AuthorizationRequest;
 
-    async fn handle_oauth_callback(&self, code: String) -> Result<OAuthResponse, ApiError>;
+    async fn handle_oauth_callback(
+        &self,
+        code: String,
+        pkce_verifier: PkceCodeVerifier,
+    ) -> Result<OAuthData, AuthError>;
 }
@@ -0,0 +1,49 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::modules::auth::{AuthError, OAuthProviderType};
+
+use super::OAuthProvider;
+
+/// The OAuth providers enabled for this deployment, keyed by their type.
+#[derive(Default)]
+pub struct ProviderRegistry {
+    providers: HashMap<OAuthProviderType, Arc<dyn OAuthProvider>>,
+}
+
+impl ProviderRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, provider: Arc<dyn OAuthProvider>) {
+        let provider_type = provider.provider_type();
+        // An OIDC provider named e.g. "google" would shadow the built-in one in the routes
+        if self
+            .providers
+            .keys()
+            .any(|registered| registered.as_str() == provider_type.as_str())
+        {
+            panic!(
+                "OAuth provider '{}' registered twice",
+                provider_type.as_str()
+            );
+        }
+        self.providers.insert(provider_type, provider);
+    }
+
+    pub fn get(
+        &self,
+        provider_type: &OAuthProviderType,
+    ) -> Result<Arc<dyn OAuthProvider>, AuthError> {
+        self.providers
+            .get(provider_type)
+            .cloned()
+            .ok_or_else(|| AuthError::UnknownProvider(provider_type.as_str().to_string()))
+    }
+
+    pub fn enabled(&self) -> Vec<OAuthProviderType> {
+        let mut provider_types: Vec<OAuthProviderType> = self.providers.keys().cloned().collect();
+        provider_types.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        provider_types
+    }
+}
@@ -1,13 +1,13 @@
-use std::{borrow::Borrow, sync::Arc};
+use std::sync::Arc;
 
-use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
 use serde::Deserialize;
-use stripe::{EventObject, EventType, Webhook};
+use stripe::{Event, Webhook};
 
 use crate::{
     error::ApiError,
-    modules::{auth::Claims, stripe_payments::Service},
-    utils::Config,
+    modules::stripe_payments::{PaymentError, Service},
+    utils::extractor::AuthUser,
 };
 
 pub async fn get_products(service: web::Data<Arc<Service>>) -> Result<HttpResponse, ApiError> {
@@ -20,65 +20,179 @@ pub struct CheckoutParams {
     product_id: String,
 }
 pub async fn get_checkout(
-    req: HttpRequest,
+    user: AuthUser,
     params: web::Query<CheckoutParams>,
     service: web::Data<Arc<Service>>,
 ) -> Result<HttpResponse, ApiError> {
-    //The middleware will take care if the claim is not present
-    if let Some(claims) = req.extensions().get::<Claims>() {
-        let user_id = claims.sub;
-
-        let url = service.create_checkout(user_id, &params.product_id).await?;
-
-        Ok(HttpResponse::Ok().json(url))
-    } else {
-        Err(ApiError::InternalServerError)
+    user.require_scope("checkout:write")?;
+    // Support acting as the customer must not spend their money
+    if user.is_impersonation() {
+        return Err(ApiError::AccessDenied);
     }
+    let url = service
+        .create_checkout(user.id(), &params.product_id)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(url))
 }
 
+/// Verifies the event and stores it in the inbox for the worker, answering 400 when
+/// it cannot be verified and 5xx when it could not be stored, so Stripe redelivers it.
 pub async fn webhook_handler(
     req: HttpRequest,
     service: web::Data<Arc<Service>>,
     payload: web::Bytes,
 ) -> HttpResponse {
-    handle_webhook(req, service, payload).await.unwrap();
-    HttpResponse::Ok().finish()
-}
+    let stripe_signature = get_header_value(&req, "Stripe-Signature");
 
-pub async fn handle_webhook(
-    req: HttpRequest,
-    service: web::Data<Arc<Service>>,
-    payload: web::Bytes,
-) -> Result<(), ApiError> {
-    let config = Config::from_env();
-    let payload_str = std::str::from_utf8(payload.borrow()).unwrap();
-
-    let stripe_signature = get_header_value(&req, "Stripe-Signature").unwrap_or_default();
-
-    if let Ok(event) =
-        Webhook::construct_event(payload_str, stripe_signature, &config.stripe_webhook_secret)
-    {
-        match event.type_ {
-            EventType::CheckoutSessionCompleted => {
-                if let EventObject::CheckoutSession(session) = event.data.object {
-                    let _ = service
-                        .create_payment_from_checkout(session.id.as_str())
-                        .await?;
-                }
+    let (event, payload) =
+        match construct_event(&payload, stripe_signature, service.webhook_secret()) {
+            Ok(event) => event,
+            Err(err) => {
+                log::warn!("Rejected webhook: {}", err);
+                return ApiError::from(err).error_response();
             }
+        };
 
-            _ => {
-                log::info!("Unknown event encountered in webhook: {:?}", event.type_);
-            }
+    match service.enqueue_event(&event, payload).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(err) => {
+            log::error!("Storing webhook event {} failed: {}", event.id, err);
+            err.error_response()
         }
-    } else {
-        log::error!("Failed to construct webhook event, ensure your webhook secret is correct.");
-        return Err(ApiError::InternalServerError);
     }
+}
+
+/// Returns the verified event with its payload as text.
+fn construct_event<'p>(
+    payload: &'p [u8],
+    stripe_signature: Option<&str>,
+    webhook_secret: &str,
+) -> Result<(Event, &'p str), PaymentError> {
+    let payload = std::str::from_utf8(payload)
+        .map_err(|_| PaymentError::InvalidWebhook("payload is not UTF-8".to_string()))?;
+    let stripe_signature = stripe_signature.ok_or_else(|| {
+        PaymentError::InvalidWebhook("missing Stripe-Signature header".to_string())
+    })?;
 
-    Ok(())
+    let event = Webhook::construct_event(payload, stripe_signature, webhook_secret)
+        .map_err(|err| PaymentError::InvalidWebhook(err.to_string()))?;
+    Ok((event, payload))
+}
+
+pub async fn replay_webhook_event(
+    event_id: web::Path<String>,
+    service: web::Data<Arc<Service>>,
+) -> Result<HttpResponse, ApiError> {
+    let inbox_event = service.replay_inbox_event(&event_id).await?;
+    Ok(HttpResponse::Accepted().json(inbox_event))
 }
 
 fn get_header_value<'b>(req: &'b HttpRequest, key: &'b str) -> Option<&'b str> {
     req.headers().get(key)?.to_str().ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+    use stripe::EventType;
+
+    use super::*;
+
+    const SECRET: &str = "whsec_test";
+
+    fn payload() -> String {
+        serde_json::json!({
+            "id": "evt_test",
+            "object": "event",
+            "api_version": "2023-10-16",
+            "created": 1700000000,
+            "livemode": false,
+            "pending_webhooks": 1,
+            "request": null,
+            "type": "customer.subscription.updated",
+            "data": {
+                "object": {
+                    "id": "sub_test",
+                    "object": "subscription",
+                    "customer": "cus_test",
+                    "status": "past_due",
+                    "automatic_tax": { "enabled": false },
+                    "billing_cycle_anchor": 1700000000,
+                    "cancel_at_period_end": false,
+                    "created": 1700000000,
+                    "currency": "usd",
+                    "current_period_start": 1700000000,
+                    "current_period_end": 1702592000,
+                    "livemode": false,
+                    "metadata": {},
+                    "start_date": 1700000000,
+                    "items": { "object": "list", "data": [], "has_more": false, "url": "/v1/subscription_items" }
+                }
+            }
+        })
+        .to_string()
+    }
+
+    fn sign(payload: &str, secret: &str, timestamp: i64) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{}.{}", timestamp, payload).as_bytes());
+        format!(
+            "t={},v1={}",
+            timestamp,
+            hex::encode(mac.finalize().into_bytes())
+        )
+    }
+
+    fn now() -> i64 {
+        chrono::Utc::now().timestamp()
+    }
+
+    fn status(result: Result<(Event, &str), PaymentError>) -> StatusCode {
+        ApiError::from(result.unwrap_err()).status_code()
+    }
+
+    #[test]
+    fn test_construct_event_accepts_signed_payload() {
+        let payload = payload();
+        let signature = sign(&payload, SECRET, now());
+
+        let (event, raw) = construct_event(payload.as_bytes(), Some(&signature), SECRET).unwrap();
+        assert_eq!(event.id.as_str(), "evt_test");
+        assert_eq!(event.type_, EventType::CustomerSubscriptionUpdated);
+        assert_eq!(raw, payload);
+    }
+
+    #[test]
+    fn test_construct_event_rejects_unverified_payloads_with_400() {
+        let payload = payload();
+
+        let wrong_secret = sign(&payload, "whsec_other", now());
+        let stale = sign(&payload, SECRET, now() - 3600);
+        let tampered = payload.replace("past_due", "active");
+        for (payload, signature) in [
+            (payload.as_str(), Some(wrong_secret.as_str())),
+            (payload.as_str(), Some(stale.as_str())),
+            (tampered.as_str(), Some(&sign(&payload, SECRET, now()))),
+            (payload.as_str(), Some("garbage")),
+            (payload.as_str(), None),
+        ] {
+            assert_eq!(
+                status(construct_event(payload.as_bytes(), signature, SECRET)),
+                StatusCode::BAD_REQUEST
+            );
+        }
+
+        let signature = sign("{}", SECRET, now());
+        assert_eq!(
+            status(construct_event(b"{}", Some(&signature), SECRET)),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(construct_event(&[0xff, 0xfe], Some(&signature), SECRET)),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
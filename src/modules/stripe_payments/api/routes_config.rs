@@ -1,15 +1,17 @@
-use crate::utils::middleware::jwt_validator;
 use actix_web::web;
 use actix_web_lab::middleware::from_fn;
 
-use super::handler::{get_checkout, get_products, webhook_handler};
+use crate::utils::middleware::require_role;
+
+use super::handler::{get_checkout, get_products, replay_webhook_event, webhook_handler};
 
 pub fn config(cfg: &mut web::ServiceConfig) {
-    cfg.service(
-        web::resource("/stripe/checkout")
-            .route(web::post().to(get_checkout))
-            .wrap(from_fn(jwt_validator)),
-    )
-    .service(web::resource("/stripe/products").route(web::get().to(get_products)))
-    .service(web::resource("/stripe/webhook").route(web::post().to(webhook_handler)));
+    cfg.service(web::resource("/stripe/checkout").route(web::post().to(get_checkout)))
+        .service(web::resource("/stripe/products").route(web::get().to(get_products)))
+        .service(web::resource("/stripe/webhook").route(web::post().to(webhook_handler)))
+        .service(
+            web::resource("/admin/webhook-events/{event_id}/replay")
+                .route(web::post().to(replay_webhook_event))
+                .wrap(from_fn(require_role("admin"))),
+        );
 }
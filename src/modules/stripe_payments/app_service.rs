@@ -1,21 +1,32 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration as StdDuration};
 
+use actix_web::ResponseError;
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use stripe::{
     CheckoutSession, CheckoutSessionMode, Client, CreateCheckoutSession,
-    CreateCheckoutSessionLineItems, CreateCustomer, Currency, Customer, CustomerId, IdOrCreate,
-    Price, Product, ProductId,
+    CreateCheckoutSessionLineItems, CreateCustomer, Currency, Customer, CustomerId, Event,
+    EventObject, EventType, IdOrCreate, Invoice, Price, PriceType, Product, ProductId, StripeError,
+    Subscription, SubscriptionId,
 };
 
 use crate::{
     error::ApiError,
     modules::{
-        subscription::{self, UserSubscription},
+        subscription::{self, SubscriptionStatus, UserSubscription},
         user::{self, User, UserError},
     },
     utils::Config,
 };
 
-use super::{ports::Repository, Payment, PaymentError, PaymentStatus};
+use super::{
+    ports::Repository, EventChanges, InboxEvent, InboxEventStatus, Payment, PaymentError,
+    PaymentStatus,
+};
+
+const INBOX_BATCH_SIZE: i64 = 10;
+/// Long enough for a batch, a claimed event is retried once it expires
+const INBOX_LEASE_MINUTES: i64 = 5;
+const INBOX_POLL_INTERVAL: StdDuration = StdDuration::from_secs(5);
 
 pub struct Service {
     repository: Arc<dyn Repository>,
@@ -119,7 +130,8 @@ impl Service {
         user_id: i32,
         product_id: &str,
     ) -> Result<String, ApiError> {
-        //If user already have this subscription return error
+        //If user already have this subscription return error. A new purchase replaces
+        //the active subscription, which Stripe would keep billing if it is recurring.
         if let Some(subscription) = self
             .subscription_service
             .get_subscription_by_user(user_id)
@@ -128,6 +140,9 @@ impl Service {
             if subscription.stripe_product_id == product_id {
                 return Err(PaymentError::AllreadyHaveProduct)?;
             }
+            if subscription.stripe_subscription_id.is_some() {
+                return Err(PaymentError::RecurringSubscriptionActive)?;
+            }
         }
 
         let user = self
@@ -145,7 +160,10 @@ impl Service {
             params.cancel_url = Some(&self.config.stripe_checkout_cancel_url);
             params.success_url = Some(&self.config.stripe_checkout_success_url);
             params.customer = Some(customer.id);
-            params.mode = Some(CheckoutSessionMode::Payment);
+            params.mode = Some(match price.type_ {
+                Some(PriceType::Recurring) => CheckoutSessionMode::Subscription,
+                _ => CheckoutSessionMode::Payment,
+            });
             params.line_items = Some(vec![CreateCheckoutSessionLineItems {
                 quantity: Some(1),
                 price: Some(price.id.to_string()),
@@ -180,20 +198,218 @@ impl Service {
     }
 }
 
-//Payment
+//Webhook events
 impl Service {
-    pub async fn create_payment_from_checkout(
+    /// Secret the signatures of webhook events are checked with.
+    pub fn webhook_secret(&self) -> &str {
+        &self.config.stripe_webhook_secret
+    }
+
+    /// Applies a webhook event once, redeliveries of a processed event are acknowledged
+    /// without changing anything.
+    pub async fn process_event(&self, event: &Event) -> Result<(), ApiError> {
+        if self
+            .repository
+            .is_event_processed(event.id.as_str())
+            .await?
+        {
+            log::info!("Webhook event {} was already processed", event.id);
+            return Ok(());
+        }
+
+        let changes = match event_action(event) {
+            EventAction::CompleteCheckout(session_id) => self.checkout_changes(&session_id).await?,
+            EventAction::SyncSubscription(subscription_id) => EventChanges {
+                subscriptions: vec![self.get_subscription(&subscription_id).await?],
+                ..Default::default()
+            },
+            EventAction::Acknowledge => EventChanges::default(),
+            EventAction::Unknown => {
+                log::info!("Unknown event encountered in webhook: {:?}", event.type_);
+                EventChanges::default()
+            }
+        };
+
+        if !self
+            .save_event_changes(event.id.as_str(), &event_type_name(event.type_), &changes)
+            .await?
+        {
+            log::info!("Webhook event {} was processed concurrently", event.id);
+        }
+        Ok(())
+    }
+
+    /// Records the event and saves its changes in one transaction, returns false
+    /// without saving anything when the event was already processed.
+    async fn save_event_changes(
         &self,
-        checkout_session_id: &str,
-    ) -> Result<(), ApiError> {
+        event_id: &str,
+        event_type: &str,
+        changes: &EventChanges,
+    ) -> Result<bool, ApiError> {
+        let mut tx = self.repository.begin().await?;
+        if !self
+            .repository
+            .record_event(&mut tx, event_id, event_type)
+            .await?
+        {
+            return Ok(false);
+        }
+
+        if let Some(payment) = &changes.payment {
+            self.repository.create_payment(&mut tx, payment).await?;
+        }
+        for subscription in &changes.subscriptions {
+            if self
+                .subscription_service
+                .save_subscription(&mut tx, subscription)
+                .await?
+                .is_none()
+            {
+                log::info!(
+                    "Webhook event {} is stale, user {} has another active subscription to {}",
+                    event_id,
+                    subscription.user_id,
+                    subscription.stripe_product_id
+                );
+            }
+        }
+
+        tx.commit().await.map_err(PaymentError::from)?;
+        Ok(true)
+    }
+}
+
+//Webhook inbox
+impl Service {
+    /// Stores a verified event for the worker, redeliveries of a stored event are ignored.
+    pub async fn enqueue_event(&self, event: &Event, payload: &str) -> Result<(), ApiError> {
+        let inbox_event =
+            InboxEvent::new(event.id.as_str(), &event_type_name(event.type_), payload);
+        if !self.repository.enqueue_event(&inbox_event).await? {
+            log::info!("Webhook event {} is already in the inbox", event.id);
+        }
+        Ok(())
+    }
+
+    /// Processes the due inbox events, returns how many were claimed. An event whose
+    /// outcome could not be recorded is due again once its lease expires.
+    pub async fn process_inbox(&self) -> Result<usize, ApiError> {
+        let inbox_events = self
+            .repository
+            .claim_due_events(INBOX_BATCH_SIZE, Duration::minutes(INBOX_LEASE_MINUTES))
+            .await?;
+        for inbox_event in &inbox_events {
+            if let Err(err) = self.process_inbox_event(inbox_event).await {
+                log::error!(
+                    "Could not record the outcome of webhook event {}: {}",
+                    inbox_event.id,
+                    err
+                );
+            }
+        }
+        Ok(inbox_events.len())
+    }
+
+    async fn process_inbox_event(&self, inbox_event: &InboxEvent) -> Result<(), ApiError> {
+        let result = match serde_json::from_str::<Event>(&inbox_event.payload) {
+            Ok(event) => self.process_event(&event).await,
+            Err(err) => Err(PaymentError::InvalidWebhook(err.to_string()).into()),
+        };
+        let Err(err) = result else {
+            self.repository
+                .complete_inbox_event(&inbox_event.id)
+                .await?;
+            return Ok(());
+        };
+
+        let retry_at = retry_at(
+            &err,
+            inbox_event,
+            self.config.webhook_max_attempts,
+            Utc::now(),
+        );
+        match retry_at {
+            Some(retry_at) => log::warn!(
+                "Webhook event {} failed, retrying at {}: {}",
+                inbox_event.id,
+                retry_at,
+                err
+            ),
+            None => log::error!(
+                "Webhook event {} failed after {} attempts, dead-lettered: {}",
+                inbox_event.id,
+                inbox_event.attempts,
+                err
+            ),
+        }
+        self.repository
+            .fail_inbox_event(&inbox_event.id, &err.to_string(), retry_at)
+            .await?;
+        Ok(())
+    }
+
+    /// Polls the inbox forever, meant to be spawned once at startup.
+    pub async fn run_inbox_worker(self: Arc<Self>) {
+        loop {
+            match self.process_inbox().await {
+                Ok(0) => tokio::time::sleep(INBOX_POLL_INTERVAL).await,
+                Ok(_) => {}
+                Err(err) => {
+                    log::error!("Webhook inbox worker failed: {}", err);
+                    tokio::time::sleep(INBOX_POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Queues a dead or failing event to be processed again.
+    pub async fn replay_inbox_event(&self, event_id: &str) -> Result<InboxEvent, ApiError> {
+        if let Some(inbox_event) = self.repository.replay_inbox_event(event_id).await? {
+            return Ok(inbox_event);
+        }
+        match self.repository.get_inbox_event(event_id).await? {
+            Some(inbox_event) if inbox_event.status == InboxEventStatus::Processed => {
+                Err(PaymentError::EventAlreadyProcessed)?
+            }
+            _ => Err(PaymentError::EventNotFound)?,
+        }
+    }
+}
+
+//Payment
+impl Service {
+    /// Whatever a completed checkout grants, a one-time purchase or a recurring
+    /// subscription depending on the checkout mode.
+    async fn checkout_changes(&self, checkout_session_id: &str) -> Result<EventChanges, ApiError> {
         let checkout_session = self.get_checkout_session_by_id(checkout_session_id).await?;
 
+        if checkout_session.mode == CheckoutSessionMode::Subscription {
+            let subscription_id = checkout_session
+                .subscription
+                .as_ref()
+                .ok_or(PaymentError::ItemNotFound)?
+                .id();
+            return Ok(EventChanges {
+                subscriptions: vec![self.get_subscription(subscription_id.as_str()).await?],
+                ..Default::default()
+            });
+        }
+
         let payment = self.create_payment(&checkout_session).await?;
-        self.repository.create_payment(&payment).await?;
 
-        self.create_user_subscription(&payment).await?;
+        //Create new subscription, saving it deactivates the previous one
+        let subscription = UserSubscription::new(
+            payment.user_id,
+            payment.stripe_product_id.clone(),
+            payment.stripe_payment_id.clone(),
+            payment.payment_date,
+        );
 
-        Ok(())
+        Ok(EventChanges {
+            payment: Some(payment),
+            subscriptions: vec![subscription],
+        })
     }
 
     async fn create_payment(
@@ -206,6 +422,19 @@ impl Service {
             .ok_or(PaymentError::ItemNotFound)?
             .id();
 
+        let (user_id, product_id) = self.get_checkout_buyer(checkout_session).await?;
+
+        let payment = Payment::new(user_id, payment_intent.as_str(), product_id.as_str())
+            .with_status(PaymentStatus::Successful);
+
+        Ok(payment)
+    }
+
+    /// The user and the product of a checkout.
+    async fn get_checkout_buyer(
+        &self,
+        checkout_session: &CheckoutSession,
+    ) -> Result<(i32, ProductId), ApiError> {
         let line_items = checkout_session
             .line_items
             .clone()
@@ -233,42 +462,322 @@ impl Service {
             .await?
             .ok_or(UserError::UserNotFound)?;
 
-        let payment = Payment::new(user.id, payment_intent.as_str(), product_id.as_str())
-            .with_status(PaymentStatus::Successful);
+        Ok((user.id, product_id))
+    }
+}
 
-        Ok(payment)
+//Recurring subscription
+impl Service {
+    /// The current state of a Stripe subscription as the `UserSubscription` of its
+    /// customer, which only grants access while the subscription is paid.
+    async fn get_subscription(&self, subscription_id: &str) -> Result<UserSubscription, ApiError> {
+        let subscription_id = subscription_id.parse::<SubscriptionId>()?;
+        let subscription =
+            Subscription::retrieve(&self.stripe_client, &subscription_id, &[]).await?;
+
+        let customer_id = subscription.customer.id();
+        let user = self
+            .user_service
+            .get_user_by_customer_id(&customer_id)
+            .await?
+            .ok_or(UserError::UserNotFound)?;
+
+        let product_id = subscription
+            .items
+            .data
+            .first()
+            .and_then(|item| item.price.as_ref())
+            .and_then(|price| price.product.as_ref())
+            .ok_or(PaymentError::ItemNotFound)?
+            .id();
+
+        Ok(UserSubscription::recurring(
+            user.id,
+            product_id.to_string(),
+            subscription.id.to_string(),
+            timestamp(subscription.start_date)?,
+        )
+        .with_status(subscription_status(subscription.status))
+        .with_period(
+            timestamp(subscription.current_period_start)?,
+            timestamp(subscription.current_period_end)?,
+        ))
     }
+}
 
-    async fn create_user_subscription(&self, payment: &Payment) -> Result<(), ApiError> {
-        let user_id = payment.user_id;
+/// What a webhook event calls for, the Stripe objects it names are fetched later.
+#[derive(Debug, PartialEq, Eq)]
+enum EventAction {
+    CompleteCheckout(String),
+    SyncSubscription(String),
+    /// A known event with nothing to save
+    Acknowledge,
+    Unknown,
+}
 
-        //Update the last subscription to non active any more
-        if let Some(subscription) = self
-            .subscription_service
-            .get_subscription_by_user(user_id)
-            .await?
-        {
-            let updated_subscription = UserSubscription {
-                is_active: false,
-                ..subscription
+fn event_action(event: &Event) -> EventAction {
+    match (event.type_, &event.data.object) {
+        (EventType::CheckoutSessionCompleted, EventObject::CheckoutSession(session)) => {
+            EventAction::CompleteCheckout(session.id.to_string())
+        }
+
+        // Refetched rather than read from the payload, events can arrive out of order
+        (
+            EventType::CustomerSubscriptionCreated
+            | EventType::CustomerSubscriptionUpdated
+            | EventType::CustomerSubscriptionDeleted,
+            EventObject::Subscription(subscription),
+        ) => EventAction::SyncSubscription(subscription.id.to_string()),
+
+        // One-time purchases are handled by their checkout
+        (
+            EventType::InvoicePaid | EventType::InvoicePaymentFailed,
+            EventObject::Invoice(Invoice {
+                subscription: Some(subscription),
+                ..
+            }),
+        ) => EventAction::SyncSubscription(subscription.id().to_string()),
+
+        (EventType::InvoicePaid | EventType::InvoicePaymentFailed, _) => EventAction::Acknowledge,
+
+        _ => EventAction::Unknown,
+    }
+}
+
+/// Whether processing may succeed on retry: database and Stripe outages, not bad data.
+fn is_transient(err: &ApiError) -> bool {
+    match err {
+        ApiError::StripeError(StripeError::Stripe(request)) => {
+            request.http_status == 429 || request.http_status >= 500
+        }
+        ApiError::StripeError(StripeError::ClientError(_) | StripeError::Timeout) => true,
+        ApiError::StripeError(_) => false,
+        _ => err.status_code().is_server_error(),
+    }
+}
+
+/// When to attempt a failed inbox event again, `None` dead-letters it. Retrying
+/// cannot fix permanent errors, those wait for a replay.
+fn retry_at(
+    err: &ApiError,
+    inbox_event: &InboxEvent,
+    max_attempts: i32,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    (is_transient(err) && inbox_event.attempts < max_attempts)
+        .then(|| now + inbox_event.retry_delay())
+}
+
+/// `EventType` displays as its JSON string, quotes included.
+fn event_type_name(event_type: EventType) -> String {
+    event_type.to_string().trim_matches('"').to_string()
+}
+
+fn subscription_status(status: stripe::SubscriptionStatus) -> SubscriptionStatus {
+    match status {
+        stripe::SubscriptionStatus::Active => SubscriptionStatus::Active,
+        stripe::SubscriptionStatus::Canceled => SubscriptionStatus::Canceled,
+        stripe::SubscriptionStatus::Incomplete => SubscriptionStatus::Incomplete,
+        stripe::SubscriptionStatus::IncompleteExpired => SubscriptionStatus::IncompleteExpired,
+        stripe::SubscriptionStatus::PastDue => SubscriptionStatus::PastDue,
+        stripe::SubscriptionStatus::Paused => SubscriptionStatus::Paused,
+        stripe::SubscriptionStatus::Trialing => SubscriptionStatus::Trialing,
+        stripe::SubscriptionStatus::Unpaid => SubscriptionStatus::Unpaid,
+    }
+}
+
+fn timestamp(timestamp: stripe::Timestamp) -> Result<DateTime<Utc>, ApiError> {
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .ok_or(ApiError::InternalServerError)
+}
+
+#[cfg(test)]
+mod tests {
+    use stripe::{ErrorType, RequestError};
+
+    use super::*;
+
+    fn stripe_error(http_status: u16) -> ApiError {
+        ApiError::StripeError(StripeError::Stripe(RequestError {
+            http_status,
+            error_type: ErrorType::Api,
+            message: None,
+            code: None,
+            decline_code: None,
+            charge: None,
+        }))
+    }
+
+    #[test]
+    fn test_only_outages_are_transient() {
+        assert!(is_transient(&stripe_error(503)));
+        assert!(is_transient(&stripe_error(429)));
+        assert!(is_transient(&StripeError::Timeout.into()));
+        assert!(is_transient(
+            &PaymentError::DatabaseError(sqlx::Error::PoolTimedOut).into()
+        ));
+
+        assert!(!is_transient(&stripe_error(404)));
+        assert!(!is_transient(&UserError::UserNotFound.into()));
+        assert!(!is_transient(&PaymentError::ItemNotFound.into()));
+        assert!(!is_transient(
+            &PaymentError::InvalidWebhook("bad payload".to_string()).into()
+        ));
+    }
+
+    fn event(type_: EventType, object: EventObject) -> Event {
+        Event {
+            type_,
+            data: stripe::NotificationEventData {
+                object,
+                previous_attributes: None,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn subscription_id() -> SubscriptionId {
+        "sub_test".parse().unwrap()
+    }
+
+    fn invoice(subscription: Option<SubscriptionId>) -> EventObject {
+        EventObject::Invoice(Invoice {
+            subscription: subscription.map(stripe::Expandable::Id),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_event_action_dispatches_lifecycle_events() {
+        let session = CheckoutSession {
+            id: "cs_test".parse().unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(
+            event_action(&event(
+                EventType::CheckoutSessionCompleted,
+                EventObject::CheckoutSession(session)
+            )),
+            EventAction::CompleteCheckout("cs_test".to_string())
+        );
+
+        for event_type in [
+            EventType::CustomerSubscriptionCreated,
+            EventType::CustomerSubscriptionUpdated,
+            EventType::CustomerSubscriptionDeleted,
+        ] {
+            let subscription = Subscription {
+                id: subscription_id(),
+                ..Default::default()
             };
+            assert_eq!(
+                event_action(&event(event_type, EventObject::Subscription(subscription))),
+                EventAction::SyncSubscription("sub_test".to_string())
+            );
+        }
 
-            self.subscription_service
-                .update_subscription(&updated_subscription)
-                .await?;
-            return Ok(());
+        for event_type in [EventType::InvoicePaid, EventType::InvoicePaymentFailed] {
+            assert_eq!(
+                event_action(&event(event_type, invoice(Some(subscription_id())))),
+                EventAction::SyncSubscription("sub_test".to_string())
+            );
         }
+    }
 
-        //Create new subscription
-        let user_subscription = UserSubscription::new(
-            user_id,
-            payment.stripe_product_id.clone(),
-            payment.stripe_payment_id.clone(),
-            payment.payment_date,
+    #[test]
+    fn test_event_action_acknowledges_invoices_without_subscription() {
+        for event_type in [EventType::InvoicePaid, EventType::InvoicePaymentFailed] {
+            assert_eq!(
+                event_action(&event(event_type, invoice(None))),
+                EventAction::Acknowledge
+            );
+        }
+        assert_eq!(
+            event_action(&event(
+                EventType::InvoiceCreated,
+                invoice(Some(subscription_id()))
+            )),
+            EventAction::Unknown
+        );
+    }
+
+    #[test]
+    fn test_subscription_status_maps_every_stripe_status() {
+        for (stripe_status, status) in [
+            (
+                stripe::SubscriptionStatus::Active,
+                SubscriptionStatus::Active,
+            ),
+            (
+                stripe::SubscriptionStatus::Canceled,
+                SubscriptionStatus::Canceled,
+            ),
+            (
+                stripe::SubscriptionStatus::Incomplete,
+                SubscriptionStatus::Incomplete,
+            ),
+            (
+                stripe::SubscriptionStatus::IncompleteExpired,
+                SubscriptionStatus::IncompleteExpired,
+            ),
+            (
+                stripe::SubscriptionStatus::PastDue,
+                SubscriptionStatus::PastDue,
+            ),
+            (
+                stripe::SubscriptionStatus::Paused,
+                SubscriptionStatus::Paused,
+            ),
+            (
+                stripe::SubscriptionStatus::Trialing,
+                SubscriptionStatus::Trialing,
+            ),
+            (
+                stripe::SubscriptionStatus::Unpaid,
+                SubscriptionStatus::Unpaid,
+            ),
+        ] {
+            assert_eq!(subscription_status(stripe_status), status);
+            assert_eq!(stripe_status.as_str(), status.as_str());
+        }
+    }
+
+    fn inbox_event(attempts: i32) -> InboxEvent {
+        InboxEvent {
+            attempts,
+            ..InboxEvent::new("evt_test", "invoice.paid", "{}")
+        }
+    }
+
+    #[test]
+    fn test_transient_error_under_the_limit_is_retried() {
+        let now = Utc::now();
+        let event = inbox_event(2);
+        assert_eq!(
+            retry_at(&stripe_error(503), &event, 3, now),
+            Some(now + event.retry_delay())
+        );
+    }
+
+    #[test]
+    fn test_transient_error_at_the_limit_is_dead_lettered() {
+        assert_eq!(
+            retry_at(&stripe_error(503), &inbox_event(3), 3, Utc::now()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_permanent_error_is_dead_lettered() {
+        assert_eq!(
+            retry_at(
+                &UserError::UserNotFound.into(),
+                &inbox_event(1),
+                3,
+                Utc::now()
+            ),
+            None
         );
-        self.subscription_service
-            .create_subscription(&user_subscription)
-            .await?;
-        Ok(())
     }
 }
@@ -20,4 +20,16 @@ pub enum PaymentError {
 
     #[error("Allready have this product")]
     AllreadyHaveProduct,
+
+    #[error("Cancel the recurring subscription before buying another product")]
+    RecurringSubscriptionActive,
+
+    #[error("Invalid webhook: {0}")]
+    InvalidWebhook(String),
+
+    #[error("Webhook event not found")]
+    EventNotFound,
+
+    #[error("Webhook event was already processed")]
+    EventAlreadyProcessed,
 }
@@ -1,7 +1,9 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 
+use crate::modules::subscription::UserSubscription;
+
 use super::PaymentError;
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
@@ -75,3 +77,84 @@ impl Payment {
         self
     }
 }
+
+/// The writes of a webhook event, saved in the same transaction as its event id.
+#[derive(Debug, Default)]
+pub struct EventChanges {
+    pub payment: Option<Payment>,
+    pub subscriptions: Vec<UserSubscription>,
+}
+
+/// Backoff of the first retry, doubled after every failed attempt.
+const INBOX_RETRY_BASE_SECONDS: i64 = 30;
+const INBOX_RETRY_MAX_SECONDS: i64 = 6 * 60 * 60;
+
+/// A verified webhook event, kept in the inbox until the worker processed it.
+#[derive(Debug, Serialize, FromRow)]
+pub struct InboxEvent {
+    pub id: String,
+    pub event_type: String,
+    /// The event as Stripe sent it
+    #[serde(skip)]
+    pub payload: String,
+    pub status: InboxEventStatus,
+    /// Counted when the worker claims the event, so a crash counts as an attempt too
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub received_at: DateTime<Utc>,
+    pub processed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "inbox_event_status", rename_all = "lowercase")]
+pub enum InboxEventStatus {
+    Pending,
+    Processed,
+    /// Failed for good or out of attempts, only an admin replay processes it again
+    Dead,
+}
+
+impl InboxEvent {
+    pub fn new(id: &str, event_type: &str, payload: &str) -> InboxEvent {
+        let now = Utc::now();
+        InboxEvent {
+            id: id.to_string(),
+            event_type: event_type.to_string(),
+            payload: payload.to_string(),
+            status: InboxEventStatus::Pending,
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            received_at: now,
+            processed_at: None,
+        }
+    }
+
+    /// Delay before retrying after the current attempt failed: 30s, 1m, 2m... up to 6h.
+    pub fn retry_delay(&self) -> Duration {
+        let doublings = (self.attempts - 1).clamp(0, 20) as u32;
+        Duration::seconds((INBOX_RETRY_BASE_SECONDS << doublings).min(INBOX_RETRY_MAX_SECONDS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inbox_retry_delay_backs_off_exponentially() {
+        let mut event = InboxEvent::new("evt_test", "invoice.paid", "{}");
+        let delays: Vec<i64> = (1..=5)
+            .map(|attempts| {
+                event.attempts = attempts;
+                event.retry_delay().num_seconds()
+            })
+            .collect();
+        assert_eq!(delays, vec![30, 60, 120, 240, 480]);
+
+        event.attempts = 100;
+        assert_eq!(event.retry_delay().num_seconds(), INBOX_RETRY_MAX_SECONDS);
+    }
+}
@@ -1,14 +1,53 @@
 use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{PgConnection, Postgres, Transaction};
 
-use super::{Payment, PaymentError, PaymentStatus};
+use super::{InboxEvent, Payment, PaymentError, PaymentStatus};
 
 #[async_trait]
 pub trait Repository: Send + Sync {
     async fn get_payment_by_user(&self, user_id: i32) -> Result<Option<Payment>, PaymentError>;
-    async fn create_payment(&self, payment: &Payment) -> Result<Payment, PaymentError>;
+    async fn create_payment(
+        &self,
+        conn: &mut PgConnection,
+        payment: &Payment,
+    ) -> Result<Payment, PaymentError>;
     async fn update_payment_status(
         &self,
         stripe_payment_id: &str,
         new_status: PaymentStatus,
     ) -> Result<(), PaymentError>;
+
+    /// Starts the transaction a webhook event is recorded in together with its changes.
+    async fn begin(&self) -> Result<Transaction<'static, Postgres>, PaymentError>;
+    async fn is_event_processed(&self, event_id: &str) -> Result<bool, PaymentError>;
+    /// Returns false when the event was already recorded. A concurrent transaction
+    /// recording the same event blocks until it ends.
+    async fn record_event(
+        &self,
+        conn: &mut PgConnection,
+        event_id: &str,
+        event_type: &str,
+    ) -> Result<bool, PaymentError>;
+
+    /// Returns false when the event is already in the inbox.
+    async fn enqueue_event(&self, event: &InboxEvent) -> Result<bool, PaymentError>;
+    /// Leases up to `limit` due events to the caller, they are due again after `lease`
+    /// if the caller dies before recording the outcome.
+    async fn claim_due_events(
+        &self,
+        limit: i64,
+        lease: Duration,
+    ) -> Result<Vec<InboxEvent>, PaymentError>;
+    async fn complete_inbox_event(&self, event_id: &str) -> Result<(), PaymentError>;
+    /// Schedules another attempt at `retry_at`, or dead-letters the event without one.
+    async fn fail_inbox_event(
+        &self,
+        event_id: &str,
+        error: &str,
+        retry_at: Option<DateTime<Utc>>,
+    ) -> Result<(), PaymentError>;
+    async fn get_inbox_event(&self, event_id: &str) -> Result<Option<InboxEvent>, PaymentError>;
+    /// Makes an unprocessed event due now with fresh attempts.
+    async fn replay_inbox_event(&self, event_id: &str) -> Result<Option<InboxEvent>, PaymentError>;
 }
@@ -1,8 +1,12 @@
 use crate::{
-    modules::stripe_payments::{ports::Repository, Payment, PaymentError, PaymentStatus},
+    modules::stripe_payments::{
+        ports::Repository, InboxEvent, Payment, PaymentError, PaymentStatus,
+    },
     utils::PostgresRepository,
 };
 use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{PgConnection, Postgres, Transaction};
 
 #[async_trait]
 impl Repository for PostgresRepository {
@@ -15,7 +19,11 @@ impl Repository for PostgresRepository {
             .map_err(|e| e.into())
     }
 
-    async fn create_payment(&self, payment: &Payment) -> Result<Payment, PaymentError> {
+    async fn create_payment(
+        &self,
+        conn: &mut PgConnection,
+        payment: &Payment,
+    ) -> Result<Payment, PaymentError> {
         let query = "
             INSERT INTO payments (stripe_payment_id,user_id, stripe_product_id, payment_date, payment_status)
             VALUES ($1, $2, $3, $4, $5::payment_status )
@@ -27,7 +35,7 @@ impl Repository for PostgresRepository {
             .bind(&payment.stripe_product_id)
             .bind(payment.payment_date)
             .bind(&payment.payment_status.to_string())
-            .fetch_one(&*self.pg_pool)
+            .fetch_one(conn)
             .await
             .map_err(|e| e.into())
     }
@@ -48,4 +56,137 @@ impl Repository for PostgresRepository {
             .map_err(|e| e.into())
             .map(|_| ())
     }
+
+    async fn begin(&self) -> Result<Transaction<'static, Postgres>, PaymentError> {
+        self.pg_pool.begin().await.map_err(|e| e.into())
+    }
+
+    async fn is_event_processed(&self, event_id: &str) -> Result<bool, PaymentError> {
+        let query = "SELECT EXISTS (SELECT 1 FROM stripe_events WHERE id = $1)";
+        sqlx::query_scalar::<_, bool>(query)
+            .bind(event_id)
+            .fetch_one(&*self.pg_pool)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    async fn record_event(
+        &self,
+        conn: &mut PgConnection,
+        event_id: &str,
+        event_type: &str,
+    ) -> Result<bool, PaymentError> {
+        let query = "
+            INSERT INTO stripe_events (id, event_type)
+            VALUES ($1, $2)
+            ON CONFLICT (id) DO NOTHING";
+        sqlx::query(query)
+            .bind(event_id)
+            .bind(event_type)
+            .execute(conn)
+            .await
+            .map(|result| result.rows_affected() > 0)
+            .map_err(|e| e.into())
+    }
+
+    async fn enqueue_event(&self, event: &InboxEvent) -> Result<bool, PaymentError> {
+        let query = "
+            INSERT INTO webhook_inbox (id, event_type, payload, status, attempts, next_attempt_at, received_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (id) DO NOTHING";
+        sqlx::query(query)
+            .bind(&event.id)
+            .bind(&event.event_type)
+            .bind(&event.payload)
+            .bind(event.status)
+            .bind(event.attempts)
+            .bind(event.next_attempt_at)
+            .bind(event.received_at)
+            .execute(&*self.pg_pool)
+            .await
+            .map(|result| result.rows_affected() > 0)
+            .map_err(|e| e.into())
+    }
+
+    async fn claim_due_events(
+        &self,
+        limit: i64,
+        lease: Duration,
+    ) -> Result<Vec<InboxEvent>, PaymentError> {
+        // SKIP LOCKED lets several workers claim disjoint batches
+        let query = "
+            UPDATE webhook_inbox
+            SET attempts = attempts + 1, next_attempt_at = NOW() + $2
+            WHERE id IN (
+                SELECT id FROM webhook_inbox
+                WHERE status = 'pending' AND next_attempt_at <= NOW()
+                ORDER BY next_attempt_at
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING *";
+        sqlx::query_as::<_, InboxEvent>(query)
+            .bind(limit)
+            .bind(lease)
+            .fetch_all(&*self.pg_pool)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    async fn complete_inbox_event(&self, event_id: &str) -> Result<(), PaymentError> {
+        let query = "
+            UPDATE webhook_inbox
+            SET status = 'processed', processed_at = NOW(), last_error = NULL
+            WHERE id = $1";
+        sqlx::query(query)
+            .bind(event_id)
+            .execute(&*self.pg_pool)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.into())
+    }
+
+    async fn fail_inbox_event(
+        &self,
+        event_id: &str,
+        error: &str,
+        retry_at: Option<DateTime<Utc>>,
+    ) -> Result<(), PaymentError> {
+        let query = "
+            UPDATE webhook_inbox
+            SET status = CASE WHEN $3::TIMESTAMPTZ IS NULL THEN 'dead' ELSE 'pending' END::inbox_event_status,
+                next_attempt_at = COALESCE($3, next_attempt_at),
+                last_error = $2
+            WHERE id = $1";
+        sqlx::query(query)
+            .bind(event_id)
+            .bind(error)
+            .bind(retry_at)
+            .execute(&*self.pg_pool)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.into())
+    }
+
+    async fn get_inbox_event(&self, event_id: &str) -> Result<Option<InboxEvent>, PaymentError> {
+        let query = "SELECT * FROM webhook_inbox WHERE id = $1";
+        sqlx::query_as::<_, InboxEvent>(query)
+            .bind(event_id)
+            .fetch_optional(&*self.pg_pool)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    async fn replay_inbox_event(&self, event_id: &str) -> Result<Option<InboxEvent>, PaymentError> {
+        let query = "
+            UPDATE webhook_inbox
+            SET status = 'pending', attempts = 0, next_attempt_at = NOW()
+            WHERE id = $1 AND status <> 'processed'
+            RETURNING *";
+        sqlx::query_as::<_, InboxEvent>(query)
+            .bind(event_id)
+            .fetch_optional(&*self.pg_pool)
+            .await
+            .map_err(|e| e.into())
+    }
 }
@@ -0,0 +1,30 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::modules::subscription::UserSubscription;
+
+#[derive(Debug, Serialize)]
+pub struct SubscriptionResponse {
+    pub user_id: i32,
+    pub stripe_product_id: String,
+    pub subscription_date: DateTime<Utc>,
+    pub is_active: bool,
+    pub status: &'static str,
+    /// Only set for recurring subscriptions, which renew at its end
+    pub current_period_start: Option<DateTime<Utc>>,
+    pub current_period_end: Option<DateTime<Utc>>,
+}
+
+impl From<UserSubscription> for SubscriptionResponse {
+    fn from(subscription: UserSubscription) -> Self {
+        SubscriptionResponse {
+            user_id: subscription.user_id,
+            stripe_product_id: subscription.stripe_product_id,
+            subscription_date: subscription.subscription_date,
+            is_active: subscription.is_active,
+            status: subscription.status.as_str(),
+            current_period_start: subscription.current_period_start,
+            current_period_end: subscription.current_period_end,
+        }
+    }
+}
@@ -4,6 +4,8 @@ use actix_web::{web, HttpResponse};
 
 use crate::{error::ApiError, modules::subscription::Service};
 
+use super::SubscriptionResponse;
+
 pub async fn get_subscription(
     service: web::Data<Arc<Service>>,
     user_id: web::Path<i32>,
@@ -11,7 +13,7 @@ pub async fn get_subscription(
     let user_id = user_id.into_inner();
     let subscription = service.get_subscription_by_user(user_id).await?;
     if let Some(subscription) = subscription {
-        Ok(HttpResponse::Ok().json(subscription))
+        Ok(HttpResponse::Ok().json(SubscriptionResponse::from(subscription)))
     } else {
         Ok(HttpResponse::NotFound().finish())
     }
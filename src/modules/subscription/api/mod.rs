@@ -1,3 +1,6 @@
+mod dto;
+pub use dto::*;
+
 pub mod handler;
 mod routes_config;
 pub use routes_config::*;
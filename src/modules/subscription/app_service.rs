@@ -1,5 +1,7 @@
 use std::sync::Arc;
 
+use sqlx::PgConnection;
+
 use crate::error::ApiError;
 
 use super::{ports::Repository, UserSubscription};
@@ -35,4 +37,15 @@ impl Service {
         let subscription = self.repository.update_subscription(subscription).await?;
         Ok(subscription)
     }
+
+    pub async fn save_subscription(
+        &self,
+        conn: &mut PgConnection,
+        subscription: &UserSubscription,
+    ) -> Result<Option<UserSubscription>, ApiError> {
+        Ok(self
+            .repository
+            .save_subscription(conn, subscription)
+            .await?)
+    }
 }
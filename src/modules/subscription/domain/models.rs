@@ -1,14 +1,55 @@
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 
-#[derive(Debug, Serialize, Deserialize, FromRow)]
+#[derive(Debug, FromRow)]
 pub struct UserSubscription {
     pub user_id: i32,
     pub stripe_product_id: String,
-    pub stripe_payment_id: String,
+    /// The payment of a one-time purchase, recurring subscriptions are paid by invoices
+    pub stripe_payment_id: Option<String>,
     pub subscription_date: DateTime<Utc>,
     pub is_active: bool,
+    pub stripe_subscription_id: Option<String>,
+    pub status: SubscriptionStatus,
+    pub current_period_start: Option<DateTime<Utc>>,
+    pub current_period_end: Option<DateTime<Utc>>,
+}
+
+/// The Stripe subscription status, one-time purchases are `Active` for good.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "subscription_status", rename_all = "snake_case")]
+pub enum SubscriptionStatus {
+    Incomplete,
+    IncompleteExpired,
+    Trialing,
+    Active,
+    PastDue,
+    Canceled,
+    Unpaid,
+    Paused,
+}
+
+impl SubscriptionStatus {
+    /// Past due subscriptions keep access while Stripe retries the payment.
+    pub fn grants_access(&self) -> bool {
+        matches!(
+            self,
+            SubscriptionStatus::Active | SubscriptionStatus::Trialing | SubscriptionStatus::PastDue
+        )
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubscriptionStatus::Incomplete => "incomplete",
+            SubscriptionStatus::IncompleteExpired => "incomplete_expired",
+            SubscriptionStatus::Trialing => "trialing",
+            SubscriptionStatus::Active => "active",
+            SubscriptionStatus::PastDue => "past_due",
+            SubscriptionStatus::Canceled => "canceled",
+            SubscriptionStatus::Unpaid => "unpaid",
+            SubscriptionStatus::Paused => "paused",
+        }
+    }
 }
 
 impl UserSubscription {
@@ -21,9 +62,105 @@ impl UserSubscription {
         UserSubscription {
             user_id,
             stripe_product_id,
-            stripe_payment_id,
+            stripe_payment_id: Some(stripe_payment_id),
+            subscription_date,
+            is_active: true,
+            stripe_subscription_id: None,
+            status: SubscriptionStatus::Active,
+            current_period_start: None,
+            current_period_end: None,
+        }
+    }
+
+    /// A recurring subscription, its status and period are kept up to date by webhooks.
+    pub fn recurring(
+        user_id: i32,
+        stripe_product_id: String,
+        stripe_subscription_id: String,
+        subscription_date: DateTime<Utc>,
+    ) -> UserSubscription {
+        UserSubscription {
+            user_id,
+            stripe_product_id,
+            stripe_payment_id: None,
             subscription_date,
             is_active: true,
+            stripe_subscription_id: Some(stripe_subscription_id),
+            status: SubscriptionStatus::Active,
+            current_period_start: None,
+            current_period_end: None,
         }
     }
+
+    pub fn with_status(mut self, status: SubscriptionStatus) -> Self {
+        self.status = status;
+        self.is_active = status.grants_access();
+        self
+    }
+
+    pub fn with_period(mut self, start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+        self.current_period_start = Some(start);
+        self.current_period_end = Some(end);
+        self
+    }
+
+    /// Whether this may overwrite the stored row of the same user and product. An
+    /// active row only takes changes of its own Stripe subscription, so a late event
+    /// of a canceled one does not end the subscription that replaced it.
+    pub fn can_replace(&self, stored: &UserSubscription) -> bool {
+        !stored.is_active || stored.stripe_subscription_id == self.stripe_subscription_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recurring(stripe_subscription_id: &str, status: SubscriptionStatus) -> UserSubscription {
+        UserSubscription::recurring(
+            1,
+            "prod_test".to_string(),
+            stripe_subscription_id.to_string(),
+            Utc::now(),
+        )
+        .with_status(status)
+    }
+
+    #[test]
+    fn test_with_status_grants_access_while_paid_or_retrying_payment() {
+        for status in [
+            SubscriptionStatus::Active,
+            SubscriptionStatus::Trialing,
+            SubscriptionStatus::PastDue,
+        ] {
+            assert!(recurring("sub_A", status).is_active, "{:?}", status);
+        }
+        for status in [
+            SubscriptionStatus::Unpaid,
+            SubscriptionStatus::Canceled,
+            SubscriptionStatus::Incomplete,
+            SubscriptionStatus::IncompleteExpired,
+            SubscriptionStatus::Paused,
+        ] {
+            let subscription = recurring("sub_A", status);
+            assert!(!subscription.is_active, "{:?}", status);
+            assert_eq!(subscription.status, status);
+        }
+    }
+
+    #[test]
+    fn test_late_event_of_another_subscription_does_not_replace_an_active_row() {
+        let stored = recurring("sub_B", SubscriptionStatus::Active);
+        assert!(!recurring("sub_A", SubscriptionStatus::Canceled).can_replace(&stored));
+        assert!(!recurring("sub_A", SubscriptionStatus::Unpaid).can_replace(&stored));
+    }
+
+    #[test]
+    fn test_subscription_replaces_its_own_row_or_an_inactive_one() {
+        let stored = recurring("sub_A", SubscriptionStatus::Active);
+        assert!(recurring("sub_A", SubscriptionStatus::Canceled).can_replace(&stored));
+
+        let stored = recurring("sub_A", SubscriptionStatus::Canceled);
+        assert!(recurring("sub_B", SubscriptionStatus::Active).can_replace(&stored));
+    }
 }
@@ -1,9 +1,11 @@
 use async_trait::async_trait;
+use sqlx::PgConnection;
 
 use super::{SubscriptionError, UserSubscription};
 
 #[async_trait]
 pub trait Repository: Send + Sync {
+    /// The active subscription, the newest one should several be active.
     async fn get_subscription_by_user(
         &self,
         user_id: i32,
@@ -18,4 +20,14 @@ pub trait Repository: Send + Sync {
         &self,
         subscription: &UserSubscription,
     ) -> Result<UserSubscription, SubscriptionError>;
+
+    /// Creates or replaces the subscription of the user to its product, on the
+    /// connection of the caller's transaction. A user has one active subscription,
+    /// saving an active one deactivates the others. Returns `None` without saving
+    /// when the subscription cannot replace the stored row, see `can_replace`.
+    async fn save_subscription(
+        &self,
+        conn: &mut PgConnection,
+        subscription: &UserSubscription,
+    ) -> Result<Option<UserSubscription>, SubscriptionError>;
 }
@@ -3,6 +3,11 @@ use crate::{
     utils::PostgresRepository,
 };
 use async_trait::async_trait;
+use sqlx::PgConnection;
+
+const SUBSCRIPTION_COLUMNS: &str =
+    "user_id, stripe_product_id, stripe_payment_id, subscription_date, is_active, \
+    stripe_subscription_id, status, current_period_start, current_period_end";
 
 #[async_trait]
 impl Repository for PostgresRepository {
@@ -10,11 +15,13 @@ impl Repository for PostgresRepository {
         &self,
         user_id: i32,
     ) -> Result<Option<UserSubscription>, SubscriptionError> {
-        let query = "
-            SELECT user_id, stripe_product_id, stripe_payment_id, subscription_date, is_active 
-            FROM user_subscription 
-            WHERE user_id = $1 and is_active = true";
-        sqlx::query_as::<_, UserSubscription>(query)
+        let query = format!(
+            "SELECT {} FROM user_subscription WHERE user_id = $1 and is_active = true
+            ORDER BY subscription_date DESC
+            LIMIT 1",
+            SUBSCRIPTION_COLUMNS
+        );
+        sqlx::query_as::<_, UserSubscription>(&query)
             .bind(user_id)
             .fetch_optional(&*self.pg_pool)
             .await
@@ -25,16 +32,22 @@ impl Repository for PostgresRepository {
         &self,
         subscription: &UserSubscription,
     ) -> Result<UserSubscription, SubscriptionError> {
-        let query = "
-            INSERT INTO user_subscription (user_id, stripe_product_id, stripe_payment_id, subscription_date, is_active)
-            VALUES ($1, $2, $3, $4, $5)
-            RETURNING user_id, stripe_product_id, stripe_payment_id, subscription_date, is_active";
-        sqlx::query_as::<_, UserSubscription>(query)
+        let query = format!(
+            "INSERT INTO user_subscription ({})
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING {}",
+            SUBSCRIPTION_COLUMNS, SUBSCRIPTION_COLUMNS
+        );
+        sqlx::query_as::<_, UserSubscription>(&query)
             .bind(subscription.user_id)
             .bind(&subscription.stripe_product_id)
             .bind(&subscription.stripe_payment_id)
             .bind(subscription.subscription_date)
             .bind(subscription.is_active)
+            .bind(&subscription.stripe_subscription_id)
+            .bind(subscription.status)
+            .bind(subscription.current_period_start)
+            .bind(subscription.current_period_end)
             .fetch_one(&*self.pg_pool)
             .await
             .map_err(SubscriptionError::from)
@@ -44,19 +57,89 @@ impl Repository for PostgresRepository {
         &self,
         subscription: &UserSubscription,
     ) -> Result<UserSubscription, SubscriptionError> {
-        let query = "
-            UPDATE user_subscription
-            SET stripe_payment_id = $2, subscription_date = $3, is_active = $4
+        let query = format!(
+            "UPDATE user_subscription
+            SET stripe_payment_id = $2, subscription_date = $3, is_active = $4,
+                stripe_subscription_id = $6, status = $7,
+                current_period_start = $8, current_period_end = $9
             WHERE user_id = $1 AND stripe_product_id = $5
-            RETURNING user_id, stripe_product_id, stripe_payment_id, subscription_date, is_active";
-        sqlx::query_as::<_, UserSubscription>(query)
+            RETURNING {}",
+            SUBSCRIPTION_COLUMNS
+        );
+        sqlx::query_as::<_, UserSubscription>(&query)
             .bind(subscription.user_id)
             .bind(&subscription.stripe_payment_id)
             .bind(subscription.subscription_date)
             .bind(subscription.is_active)
             .bind(&subscription.stripe_product_id)
+            .bind(&subscription.stripe_subscription_id)
+            .bind(subscription.status)
+            .bind(subscription.current_period_start)
+            .bind(subscription.current_period_end)
             .fetch_one(&*self.pg_pool)
             .await
             .map_err(SubscriptionError::from)
     }
+
+    async fn save_subscription(
+        &self,
+        conn: &mut PgConnection,
+        subscription: &UserSubscription,
+    ) -> Result<Option<UserSubscription>, SubscriptionError> {
+        // Locked until the transaction ends, so the check still holds when writing
+        let query = format!(
+            "SELECT {} FROM user_subscription
+            WHERE user_id = $1 AND stripe_product_id = $2
+            FOR UPDATE",
+            SUBSCRIPTION_COLUMNS
+        );
+        let stored = sqlx::query_as::<_, UserSubscription>(&query)
+            .bind(subscription.user_id)
+            .bind(&subscription.stripe_product_id)
+            .fetch_optional(&mut *conn)
+            .await?;
+        if stored.is_some_and(|stored| !subscription.can_replace(&stored)) {
+            return Ok(None);
+        }
+
+        let query = format!(
+            "INSERT INTO user_subscription ({})
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            ON CONFLICT (user_id, stripe_product_id) DO UPDATE
+            SET stripe_payment_id = EXCLUDED.stripe_payment_id,
+                subscription_date = EXCLUDED.subscription_date,
+                is_active = EXCLUDED.is_active,
+                stripe_subscription_id = EXCLUDED.stripe_subscription_id,
+                status = EXCLUDED.status,
+                current_period_start = EXCLUDED.current_period_start,
+                current_period_end = EXCLUDED.current_period_end
+            RETURNING {}",
+            SUBSCRIPTION_COLUMNS, SUBSCRIPTION_COLUMNS
+        );
+        let saved = sqlx::query_as::<_, UserSubscription>(&query)
+            .bind(subscription.user_id)
+            .bind(&subscription.stripe_product_id)
+            .bind(&subscription.stripe_payment_id)
+            .bind(subscription.subscription_date)
+            .bind(subscription.is_active)
+            .bind(&subscription.stripe_subscription_id)
+            .bind(subscription.status)
+            .bind(subscription.current_period_start)
+            .bind(subscription.current_period_end)
+            .fetch_one(&mut *conn)
+            .await?;
+
+        if subscription.is_active {
+            let query = "
+                UPDATE user_subscription
+                SET is_active = false
+                WHERE user_id = $1 AND stripe_product_id <> $2 AND is_active = true";
+            sqlx::query(query)
+                .bind(subscription.user_id)
+                .bind(&subscription.stripe_product_id)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(Some(saved))
+    }
 }
@@ -0,0 +1,126 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::modules::{
+    auth::{ApiKey, Session},
+    user::{Identity, Role, User},
+};
+
+/// What clients get to see of a user, the OAuth and Stripe identifiers stay server side.
+#[derive(Debug, Serialize)]
+pub struct UserProfile {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub image_url: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<User> for UserProfile {
+    fn from(user: User) -> Self {
+        UserProfile {
+            id: user.id,
+            name: user.name,
+            email: user.email,
+            email_verified: user.email_verified,
+            image_url: user.image_url,
+            created_at: user.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoleResponse {
+    pub name: String,
+    pub permissions: Vec<String>,
+}
+
+impl From<Role> for RoleResponse {
+    fn from(role: Role) -> Self {
+        RoleResponse {
+            name: role.name,
+            permissions: role.permissions,
+        }
+    }
+}
+
+/// An API key without its hash, the key itself is only returned when it is created.
+#[derive(Debug, Serialize)]
+pub struct ApiKeyResponse {
+    pub id: Uuid,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<ApiKey> for ApiKeyResponse {
+    fn from(api_key: ApiKey) -> Self {
+        ApiKeyResponse {
+            id: api_key.id,
+            name: api_key.name,
+            prefix: api_key.prefix,
+            scopes: api_key.scopes,
+            created_at: api_key.created_at,
+            expires_at: api_key.expires_at,
+            last_used_at: api_key.last_used_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKeyResponse {
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKeyResponse,
+}
+
+/// A linked provider account, without its tokens.
+#[derive(Debug, Serialize)]
+pub struct IdentityResponse {
+    pub id: i32,
+    pub provider: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<Identity> for IdentityResponse {
+    fn from(identity: Identity) -> Self {
+        IdentityResponse {
+            id: identity.id,
+            provider: identity.provider,
+            email: identity.email,
+            email_verified: identity.email_verified,
+            created_at: identity.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionResponse {
+    pub id: Uuid,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    /// Whether this is the session of the token making the request
+    pub current: bool,
+}
+
+impl SessionResponse {
+    pub fn new(session: Session, current_session_id: Uuid) -> Self {
+        SessionResponse {
+            current: session.id == current_session_id,
+            id: session.id,
+            user_agent: session.user_agent,
+            ip_address: session.ip_address,
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+        }
+    }
+}
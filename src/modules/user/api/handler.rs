@@ -1,23 +1,172 @@
 use std::sync::Arc;
 
-use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use actix_web::{web, HttpResponse};
+use uuid::Uuid;
 
 use crate::{
     error::ApiError,
-    modules::{auth::Claims, user::Service},
+    modules::{
+        auth::{self, CreateApiKeyRequest},
+        user::Service,
+    },
+    utils::extractor::{AuthUser, CurrentUser},
 };
 
-pub async fn get_user(
-    req: HttpRequest,
+use super::{
+    ApiKeyResponse, CreatedApiKeyResponse, IdentityResponse, RoleResponse, SessionResponse,
+    UserProfile,
+};
+
+pub async fn get_user(user: CurrentUser) -> Result<HttpResponse, ApiError> {
+    user.auth.require_scope("user:read")?;
+    Ok(HttpResponse::Ok().json(UserProfile::from(user.user)))
+}
+
+pub async fn get_sessions(
+    user: AuthUser,
+    auth_service: web::Data<Arc<auth::Service>>,
+) -> Result<HttpResponse, ApiError> {
+    let claims = user.claims()?;
+    let sessions: Vec<SessionResponse> = auth_service
+        .get_sessions(claims.sub)
+        .await?
+        .into_iter()
+        .map(|session| SessionResponse::new(session, claims.sid))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+pub async fn delete_session(
+    user: AuthUser,
+    session_id: web::Path<Uuid>,
+    auth_service: web::Data<Arc<auth::Service>>,
+) -> Result<HttpResponse, ApiError> {
+    auth_service
+        .revoke_user_session(user.account_claims()?.sub, session_id.into_inner())
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn get_api_keys(
+    user: AuthUser,
+    auth_service: web::Data<Arc<auth::Service>>,
+) -> Result<HttpResponse, ApiError> {
+    let api_keys: Vec<ApiKeyResponse> = auth_service
+        .get_api_keys(user.claims()?.sub)
+        .await?
+        .into_iter()
+        .map(ApiKeyResponse::from)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(api_keys))
+}
+
+pub async fn create_api_key(
+    user: AuthUser,
+    auth_service: web::Data<Arc<auth::Service>>,
+    body: web::Json<CreateApiKeyRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let body = body.into_inner();
+    let (key, api_key) = auth_service
+        .create_api_key(
+            user.account_claims()?.sub,
+            &body.name,
+            body.scopes,
+            body.expires_in_days,
+        )
+        .await?;
+
+    Ok(HttpResponse::Created().json(CreatedApiKeyResponse {
+        key,
+        api_key: api_key.into(),
+    }))
+}
+
+pub async fn delete_api_key(
+    user: AuthUser,
+    api_key_id: web::Path<Uuid>,
+    auth_service: web::Data<Arc<auth::Service>>,
+) -> Result<HttpResponse, ApiError> {
+    auth_service
+        .revoke_api_key(user.account_claims()?.sub, api_key_id.into_inner())
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn get_identities(
+    user: AuthUser,
+    service: web::Data<Arc<Service>>,
+) -> Result<HttpResponse, ApiError> {
+    user.require_scope("user:read")?;
+    let identities: Vec<IdentityResponse> = service
+        .get_identities(user.id())
+        .await?
+        .into_iter()
+        .map(IdentityResponse::from)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(identities))
+}
+
+pub async fn delete_identity(
+    user: AuthUser,
+    identity_id: web::Path<i32>,
+    service: web::Data<Arc<Service>>,
+) -> Result<HttpResponse, ApiError> {
+    service
+        .unlink_identity(user.account_claims()?.sub, identity_id.into_inner())
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn get_roles(service: web::Data<Arc<Service>>) -> Result<HttpResponse, ApiError> {
+    let roles: Vec<RoleResponse> = service
+        .get_roles()
+        .await?
+        .into_iter()
+        .map(RoleResponse::from)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(roles))
+}
+
+pub async fn get_user_roles(
+    user_id: web::Path<i32>,
+    service: web::Data<Arc<Service>>,
+) -> Result<HttpResponse, ApiError> {
+    let roles: Vec<RoleResponse> = service
+        .get_user_roles(user_id.into_inner())
+        .await?
+        .into_iter()
+        .map(RoleResponse::from)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(roles))
+}
+
+pub async fn add_user_role(
+    path: web::Path<(i32, String)>,
+    service: web::Data<Arc<Service>>,
+) -> Result<HttpResponse, ApiError> {
+    let (user_id, role) = path.into_inner();
+    service.add_user_role(user_id, &role).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// The tokens of the user still carry the role, so they are revoked too.
+pub async fn remove_user_role(
+    path: web::Path<(i32, String)>,
     service: web::Data<Arc<Service>>,
+    auth_service: web::Data<Arc<auth::Service>>,
 ) -> Result<HttpResponse, ApiError> {
-    //The middleware will take care if the claim is not present
-    if let Some(claims) = req.extensions().get::<Claims>() {
-        let user_id = claims.sub;
-        let user = service.get_user_by_id(user_id).await?;
+    let (user_id, role) = path.into_inner();
+    service.remove_user_role(user_id, &role).await?;
+    auth_service.logout_all(user_id).await?;
 
-        Ok(HttpResponse::Ok().json(user))
-    } else {
-        Err(ApiError::InternalServerError)
-    }
+    Ok(HttpResponse::NoContent().finish())
 }
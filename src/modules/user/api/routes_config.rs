@@ -1,14 +1,47 @@
 use actix_web::web;
 use actix_web_lab::middleware::from_fn;
 
-use crate::utils::middleware::jwt_validator;
+use crate::utils::middleware::require_role;
 
-use super::get_user;
+use super::{
+    add_user_role, create_api_key, delete_api_key, delete_identity, delete_session, get_api_keys,
+    get_identities, get_roles, get_sessions, get_user, get_user_roles, remove_user_role,
+};
 
 pub fn config(cfg: &mut web::ServiceConfig) {
-    cfg.service(
-        web::resource("/user")
-            .route(web::get().to(get_user))
-            .wrap(from_fn(jwt_validator)),
-    );
+    cfg.service(web::resource("/user").route(web::get().to(get_user)))
+        .service(web::resource("/user/sessions").route(web::get().to(get_sessions)))
+        .service(
+            web::resource("/user/sessions/{session_id}").route(web::delete().to(delete_session)),
+        )
+        .service(
+            web::resource("/user/api-keys")
+                .route(web::get().to(get_api_keys))
+                .route(web::post().to(create_api_key)),
+        )
+        .service(
+            web::resource("/user/api-keys/{api_key_id}").route(web::delete().to(delete_api_key)),
+        )
+        .service(web::resource("/user/identities").route(web::get().to(get_identities)))
+        .service(
+            web::resource("/user/identities/{identity_id}")
+                .route(web::delete().to(delete_identity)),
+        )
+        // Resources instead of an `/admin` scope, which would hide the admin routes of other modules
+        .service(
+            web::resource("/admin/roles")
+                .route(web::get().to(get_roles))
+                .wrap(from_fn(require_role("admin"))),
+        )
+        .service(
+            web::resource("/admin/users/{user_id}/roles")
+                .route(web::get().to(get_user_roles))
+                .wrap(from_fn(require_role("admin"))),
+        )
+        .service(
+            web::resource("/admin/users/{user_id}/roles/{role}")
+                .route(web::put().to(add_user_role))
+                .route(web::delete().to(remove_user_role))
+                .wrap(from_fn(require_role("admin"))),
+        );
 }
@@ -1,8 +1,10 @@
 use std::sync::Arc;
 
+use chrono::Utc;
+
 use crate::{error::ApiError, modules::auth::OAuthData};
 
-use super::{ports::Repository, User, UserError};
+use super::{ports::Repository, Identity, Role, User, UserError};
 
 pub struct Service {
     repository: Arc<dyn Repository>,
@@ -12,22 +14,68 @@ impl Service {
         Self { repository }
     }
 
+    /// Logs in the user owning the provider identity. An unknown identity signs
+    /// up a new user, or is linked to the user with the same email when both the
+    /// provider and the existing account verified that email. Linking on an
+    /// unverified email would let anyone take over an account by registering its
+    /// address at a provider that does not check it.
     pub async fn sign_up_or_login(&self, oauth_data: OAuthData) -> Result<User, ApiError> {
+        if let Some(identity) = self
+            .repository
+            .get_identity(oauth_data.provider.as_str(), &oauth_data.user_identifier)
+            .await?
+        {
+            self.repository
+                .update_identity(&identity.updated_from(&oauth_data))
+                .await?;
+            return Ok(self
+                .repository
+                .get_user_by_id(identity.user_id)
+                .await?
+                .ok_or(UserError::UserNotFound)?);
+        }
+
         match self.repository.get_user_by_email(&oauth_data.email).await? {
-            Some(user) => {
-                if user.oauth_refresh_token != oauth_data.refresh_token {
-                    let updated_user = User {
-                        oauth_refresh_token: oauth_data.refresh_token,
-                        ..user
-                    };
-                    Ok(self.repository.update_user(&updated_user).await?)
-                } else {
-                    Ok(user)
-                }
+            Some(user) if user.email_verified && oauth_data.email_verified => {
+                log::info!(
+                    "Linking {} identity to user {} by verified email",
+                    oauth_data.provider.as_str(),
+                    user.id
+                );
+                self.repository
+                    .create_identity(&Identity::new(user.id, &oauth_data))
+                    .await?;
+                Ok(user)
             }
+            // A password sign up that never confirmed its email does not hold the
+            // address, whoever proves owning it through a provider takes it over.
+            Some(user)
+                if oauth_data.email_verified
+                    && self
+                        .repository
+                        .get_identities_by_user(user.id)
+                        .await?
+                        .is_empty() =>
+            {
+                log::info!(
+                    "{} identity takes over unverified user {}",
+                    oauth_data.provider.as_str(),
+                    user.id
+                );
+                self.repository.delete_password_hash(user.id).await?;
+                let user = self.verify_email(user).await?;
+                self.repository
+                    .create_identity(&Identity::new(user.id, &oauth_data))
+                    .await?;
+                Ok(user)
+            }
+            Some(_) => Err(UserError::AccountExists)?,
             None => {
-                let new_user = User::new(oauth_data);
-                Ok(self.repository.create_user(&new_user).await?)
+                let new_user = User::new(&oauth_data);
+                Ok(self
+                    .repository
+                    .create_user_with_identity(&new_user, &Identity::new(0, &oauth_data))
+                    .await?)
             }
         }
     }
@@ -36,6 +84,10 @@ impl Service {
         Ok(self.repository.get_user_by_id(user_id).await?)
     }
 
+    pub async fn get_user_by_email(&self, email: &str) -> Result<Option<User>, ApiError> {
+        Ok(self.repository.get_user_by_email(email).await?)
+    }
+
     pub async fn get_user_by_customer_id(
         &self,
         customer_id: &str,
@@ -46,4 +98,181 @@ impl Service {
     pub async fn update_user(&self, user: &User) -> Result<User, ApiError> {
         Ok(self.repository.update_user(user).await?)
     }
+
+    /// Signs up a user that logs in with a password instead of a provider.
+    pub async fn sign_up_with_password(
+        &self,
+        email: &str,
+        name: &str,
+        password_hash: &str,
+    ) -> Result<User, ApiError> {
+        if self.repository.get_user_by_email(email).await?.is_some() {
+            return Err(UserError::UserAlreadyExists)?;
+        }
+        let user = self
+            .repository
+            .create_user(&User {
+                id: 0,
+                name: name.to_string(),
+                email: email.to_string(),
+                email_verified: false,
+                image_url: None,
+                stripe_customer_id: None,
+                created_at: Utc::now(),
+            })
+            .await?;
+        self.repository
+            .set_password_hash(user.id, password_hash)
+            .await?;
+        Ok(user)
+    }
+
+    /// Logs in the user owning an email the caller proved to own, or signs one up.
+    /// An unverified account with that email gets verified and loses its password
+    /// and unverified identities, set up by someone who never proved owning the address.
+    pub async fn sign_up_or_login_with_email(&self, email: &str) -> Result<User, ApiError> {
+        match self.repository.get_user_by_email(email).await? {
+            Some(user) if user.email_verified => Ok(user),
+            Some(user) => {
+                self.repository.delete_password_hash(user.id).await?;
+                self.verify_email(user).await
+            }
+            None => {
+                let name = email.split('@').next().unwrap_or(email);
+                Ok(self
+                    .repository
+                    .create_user(&User {
+                        id: 0,
+                        name: name.to_string(),
+                        email: email.to_string(),
+                        email_verified: true,
+                        image_url: None,
+                        stripe_customer_id: None,
+                        created_at: Utc::now(),
+                    })
+                    .await?)
+            }
+        }
+    }
+
+    pub async fn get_password_hash(&self, user_id: i32) -> Result<Option<String>, ApiError> {
+        Ok(self.repository.get_password_hash(user_id).await?)
+    }
+
+    pub async fn set_password_hash(
+        &self,
+        user_id: i32,
+        password_hash: &str,
+    ) -> Result<(), ApiError> {
+        Ok(self
+            .repository
+            .set_password_hash(user_id, password_hash)
+            .await?)
+    }
+
+    /// Marks the email as verified once the user proved they can read its mailbox.
+    /// Identities linked without a verified email are unlinked, they may belong to
+    /// someone who registered the address at a provider that does not check it.
+    pub async fn verify_email(&self, user: User) -> Result<User, ApiError> {
+        if user.email_verified {
+            return Ok(user);
+        }
+        for identity in self.repository.get_identities_by_user(user.id).await? {
+            if !identity.email_verified {
+                log::info!(
+                    "Unlinking unverified {} identity from user {} verifying its email",
+                    identity.provider,
+                    user.id
+                );
+                self.repository.delete_identity(identity.id).await?;
+            }
+        }
+        Ok(self
+            .repository
+            .update_user(&User {
+                email_verified: true,
+                ..user
+            })
+            .await?)
+    }
+
+    pub async fn reencrypt_oauth_refresh_tokens(&self) -> Result<u64, ApiError> {
+        Ok(self.repository.reencrypt_oauth_refresh_tokens().await?)
+    }
+}
+
+//Identities
+impl Service {
+    pub async fn get_identities(&self, user_id: i32) -> Result<Vec<Identity>, ApiError> {
+        Ok(self.repository.get_identities_by_user(user_id).await?)
+    }
+
+    /// Links a provider identity to a logged in user, the email does not have to match.
+    pub async fn link_identity(
+        &self,
+        user_id: i32,
+        oauth_data: OAuthData,
+    ) -> Result<Identity, ApiError> {
+        match self
+            .repository
+            .get_identity(oauth_data.provider.as_str(), &oauth_data.user_identifier)
+            .await?
+        {
+            Some(identity) if identity.user_id == user_id => Ok(self
+                .repository
+                .update_identity(&identity.updated_from(&oauth_data))
+                .await?),
+            Some(_) => Err(UserError::IdentityAlreadyLinked)?,
+            None => Ok(self
+                .repository
+                .create_identity(&Identity::new(user_id, &oauth_data))
+                .await?),
+        }
+    }
+
+    pub async fn unlink_identity(&self, user_id: i32, identity_id: i32) -> Result<(), ApiError> {
+        let identities = self.repository.get_identities_by_user(user_id).await?;
+        if !identities.iter().any(|identity| identity.id == identity_id) {
+            return Err(UserError::IdentityNotFound)?;
+        }
+        // A password is a login method too
+        if identities.len() == 1 && self.repository.get_password_hash(user_id).await?.is_none() {
+            return Err(UserError::LastIdentity)?;
+        }
+        Ok(self.repository.delete_identity(identity_id).await?)
+    }
+}
+
+//Roles
+impl Service {
+    pub async fn get_roles(&self) -> Result<Vec<Role>, ApiError> {
+        Ok(self.repository.get_roles().await?)
+    }
+
+    pub async fn get_user_roles(&self, user_id: i32) -> Result<Vec<Role>, ApiError> {
+        Ok(self.repository.get_user_roles(user_id).await?)
+    }
+
+    pub async fn add_user_role(&self, user_id: i32, role: &str) -> Result<(), ApiError> {
+        self.get_user_by_id(user_id)
+            .await?
+            .ok_or(UserError::UserNotFound)?;
+        if !self
+            .repository
+            .get_roles()
+            .await?
+            .iter()
+            .any(|existing| existing.name == role)
+        {
+            return Err(UserError::RoleNotFound(role.to_string()))?;
+        }
+        Ok(self.repository.add_user_role(user_id, role).await?)
+    }
+
+    pub async fn remove_user_role(&self, user_id: i32, role: &str) -> Result<(), ApiError> {
+        if !self.repository.remove_user_role(user_id, role).await? {
+            return Err(UserError::RoleNotFound(role.to_string()))?;
+        }
+        Ok(())
+    }
 }
@@ -1,6 +1,8 @@
 use sqlx::Error as SqlxError;
 use thiserror::Error;
 
+use crate::utils::CipherError;
+
 #[derive(Error, Debug)]
 pub enum UserError {
     #[error("Database error")]
@@ -11,4 +13,22 @@ pub enum UserError {
 
     #[error("User not found")]
     UserNotFound,
+
+    #[error("Token encryption error: {0}")]
+    EncryptionError(#[from] CipherError),
+
+    #[error("An account with this email already exists, log in to it and link this provider")]
+    AccountExists,
+
+    #[error("This provider account is already linked to another user")]
+    IdentityAlreadyLinked,
+
+    #[error("Identity not found")]
+    IdentityNotFound,
+
+    #[error("Role not found: {0}")]
+    RoleNotFound(String),
+
+    #[error("The last login method of an account cannot be unlinked")]
+    LastIdentity,
 }
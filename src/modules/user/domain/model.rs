@@ -1,33 +1,78 @@
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 
 use crate::modules::auth::OAuthData;
 
-#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+/// A role of a user with the permissions it grants.
+#[derive(Debug, Clone, FromRow)]
+pub struct Role {
+    pub name: String,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Debug, Clone, FromRow)]
 pub struct User {
     pub id: i32,
     pub name: String,
     pub email: String,
+    pub email_verified: bool,
     pub image_url: Option<String>,
-    pub oauth_provider: String,
-    pub oauth_id: String,
     pub stripe_customer_id: Option<String>,
-    pub oauth_refresh_token: String,
     pub created_at: DateTime<Utc>,
 }
 impl User {
-    pub fn new(oauth_data: OAuthData) -> Self {
+    pub fn new(oauth_data: &OAuthData) -> Self {
         User {
             id: 0,
-            email: oauth_data.email,
-            oauth_provider: oauth_data.provider.into(),
-            oauth_id: oauth_data.user_identifier,
+            email: oauth_data.email.clone(),
+            email_verified: oauth_data.email_verified,
             stripe_customer_id: None,
-            oauth_refresh_token: oauth_data.refresh_token,
-            name: oauth_data.name,
-            image_url: oauth_data.image_url,
+            name: oauth_data.name.clone(),
+            image_url: oauth_data.image_url.clone(),
+            created_at: Utc::now(),
+        }
+    }
+}
+
+/// An account at an OAuth provider that can be used to log in as the user.
+#[derive(Debug, Clone, FromRow)]
+pub struct Identity {
+    pub id: i32,
+    pub user_id: i32,
+    pub provider: String,
+    pub provider_user_id: String,
+    pub email: String,
+    pub email_verified: bool,
+    /// Encrypted at rest by the repository.
+    pub refresh_token: String,
+    pub created_at: DateTime<Utc>,
+}
+impl Identity {
+    pub fn new(user_id: i32, oauth_data: &OAuthData) -> Self {
+        Identity {
+            id: 0,
+            user_id,
+            provider: oauth_data.provider.as_str().to_string(),
+            provider_user_id: oauth_data.user_identifier.clone(),
+            email: oauth_data.email.clone(),
+            email_verified: oauth_data.email_verified,
+            refresh_token: oauth_data.refresh_token.clone(),
             created_at: Utc::now(),
         }
     }
+
+    /// Takes the latest email and refresh token the provider returned, keeping
+    /// the stored refresh token when the provider did not issue a new one.
+    pub fn updated_from(&self, oauth_data: &OAuthData) -> Self {
+        Identity {
+            email: oauth_data.email.clone(),
+            email_verified: oauth_data.email_verified,
+            refresh_token: if oauth_data.refresh_token.is_empty() {
+                self.refresh_token.clone()
+            } else {
+                oauth_data.refresh_token.clone()
+            },
+            ..self.clone()
+        }
+    }
 }
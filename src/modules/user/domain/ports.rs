@@ -1,6 +1,6 @@
 use async_trait::async_trait;
 
-use super::{User, UserError};
+use super::{Identity, Role, User, UserError};
 
 #[async_trait]
 pub trait Repository: Send + Sync {
@@ -9,4 +9,33 @@ pub trait Repository: Send + Sync {
     async fn get_user_by_id(&self, id: i32) -> Result<Option<User>, UserError>;
     async fn update_user(&self, user: &User) -> Result<User, UserError>;
     async fn create_user(&self, user: &User) -> Result<User, UserError>;
+    /// Creates the user and its first identity in one transaction.
+    async fn create_user_with_identity(
+        &self,
+        user: &User,
+        identity: &Identity,
+    ) -> Result<User, UserError>;
+
+    async fn get_identity(
+        &self,
+        provider: &str,
+        provider_user_id: &str,
+    ) -> Result<Option<Identity>, UserError>;
+    async fn get_identities_by_user(&self, user_id: i32) -> Result<Vec<Identity>, UserError>;
+    async fn create_identity(&self, identity: &Identity) -> Result<Identity, UserError>;
+    async fn update_identity(&self, identity: &Identity) -> Result<Identity, UserError>;
+    async fn delete_identity(&self, id: i32) -> Result<(), UserError>;
+
+    async fn get_roles(&self) -> Result<Vec<Role>, UserError>;
+    async fn get_user_roles(&self, user_id: i32) -> Result<Vec<Role>, UserError>;
+    async fn add_user_role(&self, user_id: i32, role: &str) -> Result<(), UserError>;
+    /// Returns `false` if the user did not have the role.
+    async fn remove_user_role(&self, user_id: i32, role: &str) -> Result<bool, UserError>;
+
+    async fn get_password_hash(&self, user_id: i32) -> Result<Option<String>, UserError>;
+    async fn set_password_hash(&self, user_id: i32, password_hash: &str) -> Result<(), UserError>;
+    async fn delete_password_hash(&self, user_id: i32) -> Result<(), UserError>;
+    /// Encrypts every identity `refresh_token` that is in plain text or under an
+    /// older key with the active one, returns how many rows were rewritten.
+    async fn reencrypt_oauth_refresh_tokens(&self) -> Result<u64, UserError>;
 }
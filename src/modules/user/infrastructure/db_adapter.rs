@@ -1,10 +1,45 @@
 use async_trait::async_trait;
 
 use crate::{
-    modules::user::{ports::Repository, User, UserError},
+    modules::user::{ports::Repository, Identity, Role, User, UserError},
     utils::PostgresRepository,
 };
 
+/// Rows re-encrypted per transaction by `reencrypt_oauth_refresh_tokens`.
+const REENCRYPT_BATCH_SIZE: i64 = 100;
+
+/// Binds an encrypted refresh token to its identity, so it cannot be moved to another one.
+fn refresh_token_context(user_id: i32, provider: &str, provider_user_id: &str) -> String {
+    format!(
+        "user_identities:{}:{}:{}",
+        user_id, provider, provider_user_id
+    )
+}
+
+impl PostgresRepository {
+    /// Identities are stored with `refresh_token` encrypted, this gives back the plain one.
+    fn decrypt_identity(&self, identity: Identity) -> Result<Identity, UserError> {
+        let context = refresh_token_context(
+            identity.user_id,
+            &identity.provider,
+            &identity.provider_user_id,
+        );
+        Ok(Identity {
+            refresh_token: self
+                .token_cipher
+                .decrypt(&identity.refresh_token, &context)?,
+            ..identity
+        })
+    }
+
+    fn encrypt_refresh_token(&self, user_id: i32, identity: &Identity) -> String {
+        self.token_cipher.encrypt(
+            &identity.refresh_token,
+            &refresh_token_context(user_id, &identity.provider, &identity.provider_user_id),
+        )
+    }
+}
+
 #[async_trait]
 impl Repository for PostgresRepository {
     async fn update_user(&self, user: &User) -> Result<User, UserError> {
@@ -12,25 +47,21 @@ impl Repository for PostgresRepository {
         UPDATE users
         SET name = $1,
             email = $2,
-            image_url = $3,
-            oauth_provider = $4,
-            oauth_id = $5,
-            stripe_customer_id = $6,
-            oauth_refresh_token = $7,
-            created_at = $8
-        WHERE id = $9
-        RETURNING id, name, email, image_url, oauth_provider, oauth_id, stripe_customer_id, oauth_refresh_token, created_at;
+            email_verified = $3,
+            image_url = $4,
+            stripe_customer_id = $5,
+            created_at = $6
+        WHERE id = $7
+        RETURNING id, name, email, email_verified, image_url, stripe_customer_id, created_at;
     ";
         sqlx::query_as::<_, User>(query)
             .bind(&user.name)
             .bind(&user.email)
+            .bind(user.email_verified)
             .bind(&user.image_url)
-            .bind(&user.oauth_provider)
-            .bind(&user.oauth_id)
             .bind(&user.stripe_customer_id)
-            .bind(&user.oauth_refresh_token)
-            .bind(&user.created_at)
-            .bind(&user.id)
+            .bind(user.created_at)
+            .bind(user.id)
             .fetch_one(&*self.pg_pool)
             .await
             .map_err(UserError::from)
@@ -65,21 +96,278 @@ impl Repository for PostgresRepository {
 
     async fn create_user(&self, user: &User) -> Result<User, UserError> {
         let query = "
-        INSERT INTO users (name, email, image_url, oauth_provider, oauth_id, stripe_customer_id, oauth_refresh_token, created_at)
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
-        RETURNING id, name, email, image_url, oauth_provider, oauth_id, stripe_customer_id, oauth_refresh_token, created_at;
+        INSERT INTO users (name, email, email_verified, image_url, stripe_customer_id, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, name, email, email_verified, image_url, stripe_customer_id, created_at;
     ";
         sqlx::query_as::<_, User>(query)
             .bind(&user.name)
             .bind(&user.email)
+            .bind(user.email_verified)
             .bind(&user.image_url)
-            .bind(&user.oauth_provider)
-            .bind(&user.oauth_id)
             .bind(&user.stripe_customer_id)
-            .bind(&user.oauth_refresh_token)
-            .bind(&user.created_at)
+            .bind(user.created_at)
+            .fetch_one(&*self.pg_pool)
+            .await
+            .map_err(UserError::from)
+    }
+
+    async fn create_user_with_identity(
+        &self,
+        user: &User,
+        identity: &Identity,
+    ) -> Result<User, UserError> {
+        let mut tx = self.pg_pool.begin().await?;
+
+        let query = "
+        INSERT INTO users (name, email, email_verified, image_url, stripe_customer_id, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, name, email, email_verified, image_url, stripe_customer_id, created_at;
+    ";
+        let user = sqlx::query_as::<_, User>(query)
+            .bind(&user.name)
+            .bind(&user.email)
+            .bind(user.email_verified)
+            .bind(&user.image_url)
+            .bind(&user.stripe_customer_id)
+            .bind(user.created_at)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        let query = "
+            INSERT INTO user_identities (user_id, provider, provider_user_id, email, email_verified, refresh_token, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)";
+        sqlx::query(query)
+            .bind(user.id)
+            .bind(&identity.provider)
+            .bind(&identity.provider_user_id)
+            .bind(&identity.email)
+            .bind(identity.email_verified)
+            .bind(self.encrypt_refresh_token(user.id, identity))
+            .bind(identity.created_at)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(user)
+    }
+
+    async fn get_identity(
+        &self,
+        provider: &str,
+        provider_user_id: &str,
+    ) -> Result<Option<Identity>, UserError> {
+        let query = "SELECT * FROM user_identities WHERE provider = $1 AND provider_user_id = $2";
+        sqlx::query_as::<_, Identity>(query)
+            .bind(provider)
+            .bind(provider_user_id)
+            .fetch_optional(&*self.pg_pool)
+            .await
+            .map_err(UserError::from)?
+            .map(|identity| self.decrypt_identity(identity))
+            .transpose()
+    }
+
+    async fn get_identities_by_user(&self, user_id: i32) -> Result<Vec<Identity>, UserError> {
+        let query = "SELECT * FROM user_identities WHERE user_id = $1 ORDER BY created_at";
+        sqlx::query_as::<_, Identity>(query)
+            .bind(user_id)
+            .fetch_all(&*self.pg_pool)
+            .await
+            .map_err(UserError::from)?
+            .into_iter()
+            .map(|identity| self.decrypt_identity(identity))
+            .collect()
+    }
+
+    async fn create_identity(&self, identity: &Identity) -> Result<Identity, UserError> {
+        let query = "
+            INSERT INTO user_identities (user_id, provider, provider_user_id, email, email_verified, refresh_token, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING *";
+        sqlx::query_as::<_, Identity>(query)
+            .bind(identity.user_id)
+            .bind(&identity.provider)
+            .bind(&identity.provider_user_id)
+            .bind(&identity.email)
+            .bind(identity.email_verified)
+            .bind(self.encrypt_refresh_token(identity.user_id, identity))
+            .bind(identity.created_at)
+            .fetch_one(&*self.pg_pool)
+            .await
+            .map_err(UserError::from)
+            .and_then(|identity| self.decrypt_identity(identity))
+    }
+
+    async fn update_identity(&self, identity: &Identity) -> Result<Identity, UserError> {
+        let query = "
+            UPDATE user_identities
+            SET email = $1,
+                email_verified = $2,
+                refresh_token = $3
+            WHERE id = $4
+            RETURNING *";
+        sqlx::query_as::<_, Identity>(query)
+            .bind(&identity.email)
+            .bind(identity.email_verified)
+            .bind(self.encrypt_refresh_token(identity.user_id, identity))
+            .bind(identity.id)
             .fetch_one(&*self.pg_pool)
             .await
             .map_err(UserError::from)
+            .and_then(|identity| self.decrypt_identity(identity))
+    }
+
+    async fn delete_identity(&self, id: i32) -> Result<(), UserError> {
+        let query = "DELETE FROM user_identities WHERE id = $1";
+        sqlx::query(query)
+            .bind(id)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(UserError::from)
+            .map(|_| ())
+    }
+
+    async fn get_password_hash(&self, user_id: i32) -> Result<Option<String>, UserError> {
+        let query = "SELECT password_hash FROM user_passwords WHERE user_id = $1";
+        sqlx::query_scalar::<_, String>(query)
+            .bind(user_id)
+            .fetch_optional(&*self.pg_pool)
+            .await
+            .map_err(UserError::from)
+    }
+
+    async fn set_password_hash(&self, user_id: i32, password_hash: &str) -> Result<(), UserError> {
+        let query = "
+            INSERT INTO user_passwords (user_id, password_hash, updated_at)
+            VALUES ($1, $2, NOW())
+            ON CONFLICT (user_id) DO UPDATE
+            SET password_hash = EXCLUDED.password_hash,
+                updated_at = EXCLUDED.updated_at";
+        sqlx::query(query)
+            .bind(user_id)
+            .bind(password_hash)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(UserError::from)
+            .map(|_| ())
+    }
+
+    async fn get_roles(&self) -> Result<Vec<Role>, UserError> {
+        let query = "
+            SELECT r.name,
+                   ARRAY_REMOVE(ARRAY_AGG(rp.permission ORDER BY rp.permission), NULL)::TEXT[]
+                       AS permissions
+            FROM roles r
+            LEFT JOIN role_permissions rp ON rp.role_id = r.id
+            GROUP BY r.name
+            ORDER BY r.name";
+        sqlx::query_as::<_, Role>(query)
+            .fetch_all(&*self.pg_pool)
+            .await
+            .map_err(UserError::from)
+    }
+
+    async fn get_user_roles(&self, user_id: i32) -> Result<Vec<Role>, UserError> {
+        let query = "
+            SELECT r.name,
+                   ARRAY_REMOVE(ARRAY_AGG(rp.permission ORDER BY rp.permission), NULL)::TEXT[]
+                       AS permissions
+            FROM user_roles ur
+            JOIN roles r ON r.id = ur.role_id
+            LEFT JOIN role_permissions rp ON rp.role_id = r.id
+            WHERE ur.user_id = $1
+            GROUP BY r.name
+            ORDER BY r.name";
+        sqlx::query_as::<_, Role>(query)
+            .bind(user_id)
+            .fetch_all(&*self.pg_pool)
+            .await
+            .map_err(UserError::from)
+    }
+
+    async fn add_user_role(&self, user_id: i32, role: &str) -> Result<(), UserError> {
+        let query = "
+            INSERT INTO user_roles (user_id, role_id)
+            SELECT $1, id FROM roles WHERE name = $2
+            ON CONFLICT (user_id, role_id) DO NOTHING";
+        sqlx::query(query)
+            .bind(user_id)
+            .bind(role)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(UserError::from)
+            .map(|_| ())
+    }
+
+    async fn remove_user_role(&self, user_id: i32, role: &str) -> Result<bool, UserError> {
+        let query = "
+            DELETE FROM user_roles
+            WHERE user_id = $1 AND role_id = (SELECT id FROM roles WHERE name = $2)";
+        sqlx::query(query)
+            .bind(user_id)
+            .bind(role)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(UserError::from)
+            .map(|result| result.rows_affected() == 1)
+    }
+
+    async fn delete_password_hash(&self, user_id: i32) -> Result<(), UserError> {
+        let query = "DELETE FROM user_passwords WHERE user_id = $1";
+        sqlx::query(query)
+            .bind(user_id)
+            .execute(&*self.pg_pool)
+            .await
+            .map_err(UserError::from)
+            .map(|_| ())
+    }
+
+    async fn reencrypt_oauth_refresh_tokens(&self) -> Result<u64, UserError> {
+        let current_prefix = self.token_cipher.current_prefix();
+        let mut reencrypted = 0;
+        let mut last_id = 0;
+        loop {
+            let mut tx = self.pg_pool.begin().await?;
+
+            // SKIP LOCKED lets several instances run it at the same time
+            let query = "
+                SELECT id, user_id, provider, provider_user_id, refresh_token FROM user_identities
+                WHERE NOT starts_with(refresh_token, $1) AND id > $2
+                ORDER BY id
+                LIMIT $3
+                FOR UPDATE SKIP LOCKED";
+            let rows = sqlx::query_as::<_, (i32, i32, String, String, String)>(query)
+                .bind(&current_prefix)
+                .bind(last_id)
+                .bind(REENCRYPT_BATCH_SIZE)
+                .fetch_all(&mut *tx)
+                .await?;
+            let Some((id, ..)) = rows.last() else {
+                return Ok(reencrypted);
+            };
+            last_id = *id;
+
+            for (id, user_id, provider, provider_user_id, refresh_token) in rows {
+                let context = refresh_token_context(user_id, &provider, &provider_user_id);
+                // A row that cannot be decrypted is left as it is for someone to look at
+                let plaintext = match self.token_cipher.decrypt(&refresh_token, &context) {
+                    Ok(plaintext) => plaintext,
+                    Err(err) => {
+                        log::warn!("Skipped re-encrypting identity {}: {}", id, err);
+                        continue;
+                    }
+                };
+                let query = "UPDATE user_identities SET refresh_token = $1 WHERE id = $2";
+                sqlx::query(query)
+                    .bind(self.token_cipher.encrypt(&plaintext, &context))
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+                reencrypted += 1;
+            }
+
+            tx.commit().await?;
+        }
     }
 }
@@ -0,0 +1,57 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::RwLock;
+
+/// Number of entries after which stale ones are evicted on insert.
+const EVICTION_THRESHOLD: usize = 10_000;
+
+/// Small in-memory cache whose entries are only trusted for `ttl`, used in front
+/// of Postgres lookups that run on every request.
+pub struct TtlCache<K, V> {
+    entries: RwLock<HashMap<K, (V, Instant)>>,
+    ttl: Duration,
+}
+
+impl<K: Eq + Hash, V: Clone> TtlCache<K, V> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: RwLock::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    pub async fn get(&self, key: &K) -> Option<V> {
+        let entries = self.entries.read().await;
+        entries
+            .get(key)
+            .filter(|(_, inserted_at)| inserted_at.elapsed() < self.ttl)
+            .map(|(value, _)| value.clone())
+    }
+
+    pub async fn insert(&self, key: K, value: V) {
+        let mut entries = self.entries.write().await;
+        if entries.len() >= EVICTION_THRESHOLD {
+            entries.retain(|_, (_, inserted_at)| inserted_at.elapsed() < self.ttl);
+        }
+        entries.insert(key, (value, Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_entries_expire_after_ttl() {
+        let cache = TtlCache::new(Duration::from_millis(20));
+        cache.insert("jti", true).await;
+        assert_eq!(cache.get(&"jti").await, Some(true));
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(cache.get(&"jti").await, None);
+    }
+}
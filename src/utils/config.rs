@@ -1,15 +1,67 @@
-use std::env;
+use std::{env, net::IpAddr};
+
+pub struct OidcProviderConfig {
+    pub name: String,
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: String,
+}
+
+impl OidcProviderConfig {
+    fn from_env(name: &str) -> OidcProviderConfig {
+        let var = |key: &str| {
+            let var_name = format!("OIDC_{}_{}", name.to_uppercase(), key);
+            env::var(&var_name).unwrap_or_else(|_| panic!("{} not set", var_name))
+        };
+        OidcProviderConfig {
+            name: name.to_string(),
+            issuer_url: var("ISSUER_URL"),
+            client_id: var("CLIENT_ID"),
+            client_secret: var("CLIENT_SECRET"),
+            redirect_uri: var("REDIRECT_URI"),
+        }
+    }
+}
 
 pub struct Config {
     pub google_client_id: String,
     pub google_client_secret: String,
     pub google_redirect_uri: String,
+    pub github_client_id: Option<String>,
+    pub github_client_secret: Option<String>,
+    pub github_redirect_uri: Option<String>,
+    pub oidc_providers: Vec<OidcProviderConfig>,
     pub database_url: String,
     pub strip_secret: String,
     pub stripe_checkout_cancel_url: String,
     pub stripe_checkout_success_url: String,
     pub stripe_webhook_secret: String,
-    pub jwt_secret: String,
+    pub jwt_secret: Option<String>,
+    pub jwt_signing_key_id: Option<String>,
+    pub jwt_signing_key_path: Option<String>,
+    pub jwt_verification_keys: Vec<(String, String)>,
+    pub jwt_issuer: String,
+    pub jwt_audience: String,
+    pub jwt_expiration_seconds: i64,
+    pub jwt_leeway_seconds: u64,
+    pub refresh_token_expiration_days: i64,
+    pub token_encryption_key_id: Option<String>,
+    pub token_encryption_keys: Vec<(String, String)>,
+    pub smtp_url: Option<String>,
+    pub mail_outbox_path: Option<String>,
+    pub mail_from: String,
+    pub password_reset_url: String,
+    pub password_reset_expiration_minutes: i64,
+    pub magic_link_url: String,
+    pub magic_link_expiration_minutes: i64,
+    pub email_verification_url: String,
+    pub email_verification_expiration_minutes: i64,
+    pub mfa_issuer: String,
+    pub mfa_pending_expiration_minutes: i64,
+    pub impersonation_expiration_minutes: i64,
+    pub webhook_max_attempts: i32,
+    pub trusted_proxies: Vec<IpAddr>,
 }
 
 impl Config {
@@ -20,6 +72,16 @@ impl Config {
                 .expect("GOOGLE_CLIENT_SECRET not set"),
             google_redirect_uri: env::var("GOOGLE_REDIRECT_URI")
                 .expect("GOOGLE_REDIRECT_URI not set"),
+            github_client_id: env::var("GITHUB_CLIENT_ID").ok(),
+            github_client_secret: env::var("GITHUB_CLIENT_SECRET").ok(),
+            github_redirect_uri: env::var("GITHUB_REDIRECT_URI").ok(),
+            oidc_providers: env::var("OIDC_PROVIDERS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(OidcProviderConfig::from_env)
+                .collect(),
             database_url: env::var("DATABASE_URL").expect("DATABASE_URL not set"),
             strip_secret: env::var("STRIPE_SECRET").expect("STRIPE_SECRET not set"),
             stripe_checkout_cancel_url: env::var("STRIPE_CHECKOUT_CANCEL_URL")
@@ -28,7 +90,94 @@ impl Config {
                 .expect("STRIPE_CHECKOUT_SUCCESS_URL not set"),
             stripe_webhook_secret: env::var("STRIPE_WEBHOOK_SECRET")
                 .expect("STRIPE_WEBHOOK_SECRET not set"),
-            jwt_secret: env::var("JWT_SECRET").expect("JWT_SECRET not set"),
+            jwt_secret: env::var("JWT_SECRET").ok(),
+            jwt_signing_key_id: env::var("JWT_SIGNING_KEY_ID").ok(),
+            jwt_signing_key_path: env::var("JWT_SIGNING_KEY_PATH").ok(),
+            jwt_verification_keys: env::var("JWT_VERIFICATION_KEYS")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|entry| entry.trim().split_once('='))
+                .map(|(kid, path)| (kid.to_string(), path.to_string()))
+                .collect(),
+            jwt_issuer: env::var("JWT_ISSUER")
+                .unwrap_or_else(|_| "user_oauth_stripe_skeleton".to_string()),
+            jwt_audience: env::var("JWT_AUDIENCE")
+                .unwrap_or_else(|_| "user_oauth_stripe_skeleton".to_string()),
+            jwt_expiration_seconds: env::var("JWT_EXPIRATION_SECONDS")
+                .map(|v| v.parse().expect("JWT_EXPIRATION_SECONDS must be a number"))
+                .unwrap_or(3600),
+            jwt_leeway_seconds: env::var("JWT_LEEWAY_SECONDS")
+                .map(|v| v.parse().expect("JWT_LEEWAY_SECONDS must be a number"))
+                .unwrap_or(60),
+            refresh_token_expiration_days: env::var("REFRESH_TOKEN_EXPIRATION_DAYS")
+                .map(|v| {
+                    v.parse()
+                        .expect("REFRESH_TOKEN_EXPIRATION_DAYS must be a number")
+                })
+                .unwrap_or(30),
+            token_encryption_key_id: env::var("TOKEN_ENCRYPTION_KEY_ID").ok(),
+            token_encryption_keys: env::var("TOKEN_ENCRYPTION_KEYS")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|entry| entry.trim().split_once('='))
+                .map(|(kid, key)| (kid.to_string(), key.to_string()))
+                .collect(),
+            smtp_url: env::var("SMTP_URL").ok(),
+            mail_outbox_path: env::var("MAIL_OUTBOX_PATH").ok(),
+            mail_from: env::var("MAIL_FROM").unwrap_or_else(|_| "no-reply@localhost".to_string()),
+            password_reset_url: env::var("PASSWORD_RESET_URL")
+                .unwrap_or_else(|_| "http://localhost/reset-password".to_string()),
+            password_reset_expiration_minutes: env::var("PASSWORD_RESET_EXPIRATION_MINUTES")
+                .map(|v| {
+                    v.parse()
+                        .expect("PASSWORD_RESET_EXPIRATION_MINUTES must be a number")
+                })
+                .unwrap_or(30),
+            magic_link_url: env::var("MAGIC_LINK_URL")
+                .unwrap_or_else(|_| "http://localhost/magic-link".to_string()),
+            magic_link_expiration_minutes: env::var("MAGIC_LINK_EXPIRATION_MINUTES")
+                .map(|v| {
+                    v.parse()
+                        .expect("MAGIC_LINK_EXPIRATION_MINUTES must be a number")
+                })
+                .unwrap_or(15),
+            email_verification_url: env::var("EMAIL_VERIFICATION_URL")
+                .unwrap_or_else(|_| "http://localhost/verify-email".to_string()),
+            email_verification_expiration_minutes: env::var(
+                "EMAIL_VERIFICATION_EXPIRATION_MINUTES",
+            )
+            .map(|v| {
+                v.parse()
+                    .expect("EMAIL_VERIFICATION_EXPIRATION_MINUTES must be a number")
+            })
+            .unwrap_or(24 * 60),
+            mfa_issuer: env::var("MFA_ISSUER")
+                .unwrap_or_else(|_| "user_oauth_stripe_skeleton".to_string()),
+            mfa_pending_expiration_minutes: env::var("MFA_PENDING_EXPIRATION_MINUTES")
+                .map(|v| {
+                    v.parse()
+                        .expect("MFA_PENDING_EXPIRATION_MINUTES must be a number")
+                })
+                .unwrap_or(5),
+            impersonation_expiration_minutes: env::var("IMPERSONATION_EXPIRATION_MINUTES")
+                .map(|v| {
+                    v.parse()
+                        .expect("IMPERSONATION_EXPIRATION_MINUTES must be a number")
+                })
+                .unwrap_or(15),
+            webhook_max_attempts: env::var("WEBHOOK_MAX_ATTEMPTS")
+                .map(|v| v.parse().expect("WEBHOOK_MAX_ATTEMPTS must be a number"))
+                .unwrap_or(10),
+            trusted_proxies: env::var("TRUSTED_PROXIES")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|ip| !ip.is_empty())
+                .map(|ip| {
+                    ip.parse()
+                        .expect("TRUSTED_PROXIES must be a comma separated list of IP addresses")
+                })
+                .collect(),
         }
     }
 }
@@ -0,0 +1,291 @@
+use std::{collections::HashMap, fmt};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use thiserror::Error;
+
+use super::Config;
+
+/// Prefix of every value written by [`TokenCipher::encrypt`], followed by the key id.
+const ENVELOPE_PREFIX: &str = "enc:v2:";
+/// Values written before the ciphertext was bound to its row, they decrypt in any context.
+const LEGACY_ENVELOPE_PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+
+#[derive(Error, Debug)]
+pub enum CipherError {
+    #[error("Unknown encryption key: {0}")]
+    UnknownKey(String),
+
+    #[error("Malformed encrypted value")]
+    Malformed,
+
+    #[error("Decryption failed")]
+    DecryptionFailed,
+}
+
+/// Envelope encryption for secrets stored in Postgres. Every value gets its own
+/// random data key, which is wrapped with the active key-encryption key and stored
+/// next to the ciphertext as `enc:v2:<key id>:<wrapped data key>:<ciphertext>`.
+/// The ciphertext is authenticated with a context naming the row it belongs to, so it
+/// does not decrypt when copied to another row.
+/// Older keys stay configured for decryption until every row was re-encrypted.
+pub struct TokenCipher {
+    active_key_id: String,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+impl TokenCipher {
+    /// Loads the base64 encoded 256-bit keys in `TOKEN_ENCRYPTION_KEYS` and encrypts
+    /// with `TOKEN_ENCRYPTION_KEY_ID`, or with the first key when it is not set.
+    pub fn from_config(config: &Config) -> Self {
+        let keys: Vec<(String, Vec<u8>)> = config
+            .token_encryption_keys
+            .iter()
+            .map(|(kid, key)| {
+                let key = STANDARD_NO_PAD
+                    .decode(key.trim_end_matches('='))
+                    .unwrap_or_else(|_| panic!("Encryption key '{}' is not valid base64", kid));
+                (kid.clone(), key)
+            })
+            .collect();
+        let active_key_id = config
+            .token_encryption_key_id
+            .clone()
+            .or_else(|| keys.first().map(|(kid, _)| kid.clone()))
+            .expect("TOKEN_ENCRYPTION_KEYS not set");
+
+        Self::new(active_key_id, keys)
+    }
+
+    pub fn new(active_key_id: String, keys: Vec<(String, Vec<u8>)>) -> Self {
+        let keys: HashMap<String, Aes256Gcm> = keys
+            .into_iter()
+            .map(|(kid, key)| {
+                assert!(
+                    !kid.contains(':'),
+                    "Encryption key id '{}' contains ':'",
+                    kid
+                );
+                assert!(key.len() == 32, "Encryption key '{}' must be 32 bytes", kid);
+                (kid, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+            })
+            .collect();
+        assert!(
+            keys.contains_key(&active_key_id),
+            "Encryption key '{}' is not configured",
+            active_key_id
+        );
+
+        Self {
+            active_key_id,
+            keys,
+        }
+    }
+
+    pub fn encrypt(&self, plaintext: &str, context: &str) -> String {
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let wrapped_key = seal(
+            &self.keys[&self.active_key_id],
+            &data_key,
+            self.active_key_id.as_bytes(),
+        );
+        let ciphertext = seal(
+            &Aes256Gcm::new(&data_key),
+            plaintext.as_bytes(),
+            context.as_bytes(),
+        );
+
+        format!(
+            "{}{}:{}:{}",
+            ENVELOPE_PREFIX,
+            self.active_key_id,
+            STANDARD_NO_PAD.encode(wrapped_key),
+            STANDARD_NO_PAD.encode(ciphertext)
+        )
+    }
+
+    /// Values written before encryption was enabled are returned as they are.
+    pub fn decrypt(&self, value: &str, context: &str) -> Result<String, CipherError> {
+        let (envelope, context) = if let Some(envelope) = value.strip_prefix(ENVELOPE_PREFIX) {
+            (envelope, context)
+        } else if let Some(envelope) = value.strip_prefix(LEGACY_ENVELOPE_PREFIX) {
+            (envelope, "")
+        } else {
+            return Ok(value.to_string());
+        };
+
+        let mut parts = envelope.splitn(3, ':');
+        let (Some(kid), Some(wrapped_key), Some(ciphertext)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(CipherError::Malformed);
+        };
+        let key = self
+            .keys
+            .get(kid)
+            .ok_or_else(|| CipherError::UnknownKey(kid.to_string()))?;
+
+        let wrapped_key = STANDARD_NO_PAD
+            .decode(wrapped_key)
+            .map_err(|_| CipherError::Malformed)?;
+        let data_key = open(key, &wrapped_key, kid.as_bytes())?;
+        if data_key.len() != 32 {
+            return Err(CipherError::Malformed);
+        }
+
+        let ciphertext = STANDARD_NO_PAD
+            .decode(ciphertext)
+            .map_err(|_| CipherError::Malformed)?;
+        let plaintext = open(
+            &Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key)),
+            &ciphertext,
+            context.as_bytes(),
+        )?;
+        String::from_utf8(plaintext).map_err(|_| CipherError::Malformed)
+    }
+
+    /// Whether the value is already encrypted with the active key and bound to its row.
+    pub fn is_current(&self, value: &str) -> bool {
+        value.starts_with(&self.current_prefix())
+    }
+
+    /// Prefix shared by every value encrypted with the active key.
+    pub fn current_prefix(&self) -> String {
+        format!("{}{}:", ENVELOPE_PREFIX, self.active_key_id)
+    }
+}
+
+impl fmt::Debug for TokenCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenCipher")
+            .field("active_key_id", &self.active_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Encrypts with a random nonce and returns `nonce || ciphertext`.
+fn seal(cipher: &Aes256Gcm, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    let nonce = Aes256Gcm::generate_nonce(OsRng);
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .expect("AES-GCM encryption failed");
+    [nonce.as_slice(), &ciphertext].concat()
+}
+
+fn open(cipher: &Aes256Gcm, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, CipherError> {
+    if sealed.len() < NONCE_LEN {
+        return Err(CipherError::Malformed);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| CipherError::DecryptionFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTEXT: &str = "user_identities:1:google:1234";
+
+    fn cipher(active_key_id: &str) -> TokenCipher {
+        TokenCipher::new(
+            active_key_id.to_string(),
+            vec![
+                ("2024".to_string(), vec![1; 32]),
+                ("2025".to_string(), vec![2; 32]),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_and_rotate() {
+        let old = cipher("2024");
+        let encrypted = old.encrypt("1//refresh-token", CONTEXT);
+        assert!(encrypted.starts_with("enc:v2:2024:"));
+        assert_ne!(encrypted, old.encrypt("1//refresh-token", CONTEXT));
+        assert_eq!(
+            old.decrypt(&encrypted, CONTEXT).unwrap(),
+            "1//refresh-token"
+        );
+
+        // After rotating, old values still decrypt but have to be re-encrypted
+        let new = cipher("2025");
+        assert!(old.is_current(&encrypted));
+        assert!(!new.is_current(&encrypted));
+        assert_eq!(
+            new.decrypt(&encrypted, CONTEXT).unwrap(),
+            "1//refresh-token"
+        );
+
+        // Legacy plaintext is passed through until it gets re-encrypted
+        assert_eq!(new.decrypt("1//plain", CONTEXT).unwrap(), "1//plain");
+        assert!(!new.is_current("1//plain"));
+    }
+
+    #[test]
+    fn test_decrypt_requires_the_row_context() {
+        let cipher = cipher("2024");
+        let encrypted = cipher.encrypt("1//refresh-token", CONTEXT);
+
+        // Copied to another user's identity
+        assert!(matches!(
+            cipher.decrypt(&encrypted, "user_identities:2:google:1234"),
+            Err(CipherError::DecryptionFailed)
+        ));
+
+        // v1 values were not bound to a row, they decrypt until re-encrypted
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let legacy = format!(
+            "enc:v1:2024:{}:{}",
+            STANDARD_NO_PAD.encode(seal(&cipher.keys["2024"], &data_key, b"2024")),
+            STANDARD_NO_PAD.encode(seal(&Aes256Gcm::new(&data_key), b"1//legacy", &[]))
+        );
+        assert_eq!(cipher.decrypt(&legacy, CONTEXT).unwrap(), "1//legacy");
+        assert!(!cipher.is_current(&legacy));
+    }
+
+    #[test]
+    fn test_decrypt_rejects_tampering() {
+        let cipher = cipher("2024");
+        let encrypted = cipher.encrypt("1//refresh-token", CONTEXT);
+
+        // Moving the data key under another key id breaks the wrapped key's AAD
+        let relabeled = encrypted.replacen("enc:v2:2024:", "enc:v2:2025:", 1);
+        assert!(matches!(
+            cipher.decrypt(&relabeled, CONTEXT),
+            Err(CipherError::DecryptionFailed)
+        ));
+
+        let unknown = encrypted.replacen("enc:v2:2024:", "enc:v2:1999:", 1);
+        assert!(matches!(
+            cipher.decrypt(&unknown, CONTEXT),
+            Err(CipherError::UnknownKey(_))
+        ));
+
+        let (prefix, ciphertext) = encrypted.rsplit_once(':').unwrap();
+        let mut ciphertext = STANDARD_NO_PAD.decode(ciphertext).unwrap();
+        ciphertext[NONCE_LEN] ^= 1;
+        let tampered = format!("{}:{}", prefix, STANDARD_NO_PAD.encode(ciphertext));
+        assert!(matches!(
+            cipher.decrypt(&tampered, CONTEXT),
+            Err(CipherError::DecryptionFailed)
+        ));
+    }
+}
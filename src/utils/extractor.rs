@@ -0,0 +1,158 @@
+use std::sync::Arc;
+
+use actix_web::{dev::Payload, web, FromRequest, HttpMessage, HttpRequest};
+use futures::future::LocalBoxFuture;
+
+use crate::{
+    error::ApiError,
+    modules::{
+        auth::{self, api_key_prefix, ApiKey, AuthError, Claims},
+        user::{self, User},
+    },
+};
+
+/// How the caller authenticated.
+#[derive(Debug, Clone)]
+pub enum Credential {
+    Token(Claims),
+    ApiKey(ApiKey),
+}
+
+/// The caller of an authenticated route. Extracting it verifies the bearer token,
+/// an access token or an API key, so a handler taking it answers 401 without a
+/// valid one, no middleware needed.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub credential: Credential,
+}
+
+impl AuthUser {
+    pub fn id(&self) -> i32 {
+        match &self.credential {
+            Credential::Token(claims) => claims.sub,
+            Credential::ApiKey(api_key) => api_key.user_id,
+        }
+    }
+
+    /// The claims of a login, for routes that API keys must not reach, like listing
+    /// sessions.
+    pub fn claims(&self) -> Result<&Claims, ApiError> {
+        match &self.credential {
+            Credential::Token(claims) => Ok(claims),
+            Credential::ApiKey(_) => Err(ApiError::AccessDenied),
+        }
+    }
+
+    /// Like `claims`, but also refuses admins impersonating the user. For payments
+    /// and for changing how the account logs in.
+    pub fn account_claims(&self) -> Result<&Claims, ApiError> {
+        let claims = self.claims()?;
+        if claims.is_impersonation() {
+            return Err(ApiError::AccessDenied);
+        }
+        Ok(claims)
+    }
+
+    pub fn is_impersonation(&self) -> bool {
+        matches!(&self.credential, Credential::Token(claims) if claims.is_impersonation())
+    }
+
+    /// API keys and impersonation tokens never pass a role check, admin routes need
+    /// the admin's own login.
+    pub fn has_role(&self, role: &str) -> bool {
+        self.account_claims()
+            .is_ok_and(|claims| claims.has_role(role))
+    }
+
+    /// Access tokens have every scope, API keys the ones they were created with.
+    pub fn require_scope(&self, scope: &str) -> Result<(), ApiError> {
+        match &self.credential {
+            Credential::ApiKey(api_key) if !api_key.has_scope(scope) => Err(ApiError::AccessDenied),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Error = ApiError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { authenticate(&req).await })
+    }
+}
+
+/// Like [`AuthUser`], with the user loaded too.
+#[derive(Debug, Clone)]
+pub struct CurrentUser {
+    pub user: User,
+    pub auth: AuthUser,
+}
+
+impl FromRequest for CurrentUser {
+    type Error = ApiError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let auth = authenticate(&req).await?;
+            let user_service = req
+                .app_data::<web::Data<Arc<user::Service>>>()
+                .ok_or(ApiError::InternalServerError)?;
+            // The token outlived its user
+            let user = user_service
+                .get_user_by_id(auth.id())
+                .await?
+                .ok_or(AuthError::AuthorizationFailed)?;
+            Ok(CurrentUser { user, auth })
+        })
+    }
+}
+
+/// Verifies the bearer token once per request, later extractions reuse the result.
+async fn authenticate(req: &HttpRequest) -> Result<AuthUser, ApiError> {
+    if let Some(user) = req.extensions().get::<AuthUser>() {
+        return Ok(user.clone());
+    }
+
+    let token = req
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(AuthError::AuthorizationFailed)?;
+    let auth_service = req
+        .app_data::<web::Data<Arc<auth::Service>>>()
+        .ok_or(ApiError::InternalServerError)?;
+
+    let credential = match api_key_prefix(token) {
+        Some(_) => Credential::ApiKey(auth_service.authenticate_api_key(token).await?),
+        None => Credential::Token(auth_service.authenticate(token).await?),
+    };
+    let user = AuthUser { credential };
+    req.extensions_mut().insert(user.clone());
+    Ok(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::TestRequest, ResponseError};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_auth_user_requires_bearer_token() {
+        for request in [
+            TestRequest::default(),
+            TestRequest::default().insert_header(("Authorization", "Basic dXNlcjpwYXNz")),
+        ] {
+            let (req, mut payload) = request.to_http_parts();
+            let err = AuthUser::from_request(&req, &mut payload)
+                .await
+                .unwrap_err();
+            assert_eq!(err.status_code(), StatusCode::UNAUTHORIZED);
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use lettre::{message::Mailbox, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::Serialize;
+use thiserror::Error;
+
+use super::Config;
+
+#[derive(Error, Debug)]
+pub enum MailerError {
+    #[error("Invalid email address: {0}")]
+    InvalidAddress(String),
+
+    #[error("Failed to send email: {0}")]
+    SendFailed(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+pub fn is_valid_email(email: &str) -> bool {
+    email.parse::<lettre::Address>().is_ok()
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), MailerError>;
+}
+
+/// Uses SMTP when `SMTP_URL` is set, else the file outbox in `MAIL_OUTBOX_PATH`,
+/// and only logs the emails when neither is set.
+pub fn mailer_from_config(config: &Config) -> Arc<dyn Mailer> {
+    match (&config.smtp_url, &config.mail_outbox_path) {
+        (Some(smtp_url), _) => Arc::new(SmtpMailer::new(smtp_url, &config.mail_from)),
+        (None, Some(path)) => Arc::new(FileMailer::new(path)),
+        (None, None) => {
+            log::warn!("SMTP_URL not set, emails are logged instead of sent");
+            Arc::new(LogMailer)
+        }
+    }
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(smtp_url: &str, from: &str) -> Self {
+        Self {
+            transport: AsyncSmtpTransport::<Tokio1Executor>::from_url(smtp_url)
+                .expect("Invalid SMTP_URL")
+                .build(),
+            from: from.parse().expect("Invalid MAIL_FROM"),
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), MailerError> {
+        let to: Mailbox = email
+            .to
+            .parse()
+            .map_err(|_| MailerError::InvalidAddress(email.to.clone()))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(email.subject)
+            .body(email.body)
+            .map_err(|err| MailerError::SendFailed(err.to_string()))?;
+
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|err| MailerError::SendFailed(err.to_string()))
+    }
+}
+
+/// Development mailer, the body is logged because it carries the one-time links.
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: Email) -> Result<(), MailerError> {
+        log::info!("Email to {}: {}\n{}", email.to, email.subject, email.body);
+        Ok(())
+    }
+}
+
+/// Appends every email as a JSON line to a file, for end-to-end tests that read
+/// the links out of the outbox.
+pub struct FileMailer {
+    path: String,
+    lock: Mutex<()>,
+}
+
+impl FileMailer {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<(), MailerError> {
+        let mut line = serde_json::to_string(&email)
+            .map_err(|err| MailerError::SendFailed(err.to_string()))?;
+        line.push('\n');
+
+        let _guard = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .map_err(|err| MailerError::SendFailed(err.to_string()))
+    }
+}
+
+/// Keeps sent emails in memory, for tests.
+#[derive(Default)]
+pub struct InMemoryMailer {
+    outbox: Mutex<Vec<Email>>,
+}
+
+impl InMemoryMailer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sent(&self) -> Vec<Email> {
+        self.outbox
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+}
+
+#[async_trait]
+impl Mailer for InMemoryMailer {
+    async fn send(&self, email: Email) -> Result<(), MailerError> {
+        self.outbox
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push(email);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email(to: &str) -> Email {
+        Email {
+            to: to.to_string(),
+            subject: "Your login link".to_string(),
+            body: "https://example.com/magic-link?token=abc".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_mailer_keeps_sent_emails() {
+        let mailer = InMemoryMailer::new();
+        mailer.send(email("a@example.com")).await.unwrap();
+        mailer.send(email("b@example.com")).await.unwrap();
+
+        let sent = mailer.sent();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1].to, "b@example.com");
+    }
+
+    #[tokio::test]
+    async fn test_file_mailer_appends_json_lines() {
+        let path = std::env::temp_dir().join(format!("outbox-{}.jsonl", uuid::Uuid::new_v4()));
+        let mailer = FileMailer::new(path.to_str().unwrap());
+        mailer.send(email("a@example.com")).await.unwrap();
+        mailer.send(email("b@example.com")).await.unwrap();
+
+        let outbox = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<serde_json::Value> = outbox
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["to"], "a@example.com");
+        assert_eq!(lines[1]["body"], "https://example.com/magic-link?token=abc");
+    }
+}
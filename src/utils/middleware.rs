@@ -1,36 +1,38 @@
 use actix_web::{
     body::MessageBody,
     dev::{ServiceRequest, ServiceResponse},
-    error::ErrorUnauthorized,
-    Error, HttpMessage,
+    Error,
 };
 use actix_web_lab::middleware::Next;
+use futures::future::LocalBoxFuture;
 
-use crate::modules::auth::verify_jwt;
+use crate::error::ApiError;
 
-// Middleware implementation
+use super::extractor::AuthUser;
+
+/// Rejects requests without a valid bearer token, for routes whose handlers do not
+/// take [`AuthUser`] themselves. The claims are left in the request extensions.
 pub async fn jwt_validator(
-    req: ServiceRequest,
+    mut req: ServiceRequest,
     next: Next<impl MessageBody>,
 ) -> Result<ServiceResponse<impl MessageBody>, Error> {
-    // Extract JWT from the Authorization header
-    if let Some(auth_header) = req.headers().get("Authorization") {
-        if let Ok(token_str) = auth_header.to_str() {
-            if let Some(token) = token_str.strip_prefix("Bearer ") {
-                // Verify the token and proceed if valid
-                match verify_jwt(token) {
-                    Ok(claims) => {
-                        // Insert claims into the request extensions so it can be accessed in handlers
-                        req.extensions_mut().insert(claims);
-                        return next.call(req).await;
-                    }
-                    Err(e) => {
-                        return Err(ErrorUnauthorized(format!("Invalid token: {}", e)));
-                    }
-                }
+    req.extract::<AuthUser>().await?;
+    next.call(req).await
+}
+
+/// Only lets through users whose token has `role`, others get `ApiError::AccessDenied`
+/// and requests without a valid token a 401.
+pub fn require_role<B: MessageBody + 'static>(
+    role: &'static str,
+) -> impl Fn(ServiceRequest, Next<B>) -> LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>>
+{
+    move |mut req, next| {
+        Box::pin(async move {
+            let user = req.extract::<AuthUser>().await?;
+            if !user.has_role(role) {
+                return Err(ApiError::AccessDenied.into());
             }
-        }
+            next.call(req).await
+        })
     }
-
-    Err(ErrorUnauthorized("No valid Bearer token found"))
 }
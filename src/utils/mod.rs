@@ -1,7 +1,17 @@
 mod postgres;
 pub use postgres::*;
 
+pub mod extractor;
 pub mod middleware;
 
 mod config;
 pub use config::*;
+
+mod cache;
+pub use cache::*;
+
+mod crypto;
+pub use crypto::*;
+
+mod mailer;
+pub use mailer::*;
@@ -2,11 +2,12 @@ use std::sync::Arc;
 
 use sqlx::PgPool;
 
-use super::Config;
+use super::{Config, TokenCipher};
 
 #[derive(Debug, Clone)]
 pub struct PostgresRepository {
     pub pg_pool: Arc<PgPool>,
+    pub token_cipher: Arc<TokenCipher>,
 }
 
 impl PostgresRepository {
@@ -19,6 +20,7 @@ impl PostgresRepository {
         let pool = PgPool::connect(&conn_url).await.unwrap();
         Self {
             pg_pool: Arc::new(pool),
+            token_cipher: Arc::new(TokenCipher::from_config(&config)),
         }
     }
 }